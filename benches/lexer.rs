@@ -0,0 +1,75 @@
+//! Lexes and parses a generated source file of about 1 MB.
+//!
+//! Run with `cargo bench --bench lexer`.
+
+use std::time::{Duration, Instant};
+
+use vm::parser::{
+    tokenizer::{TokenKind, Tokenizer},
+    Parser,
+};
+
+const SOURCE_SIZE: usize = 1024 * 1024;
+const ITERATIONS: u32 = 10;
+
+fn main() {
+    let source = generate_source(SOURCE_SIZE);
+
+    let tokens = bench("lex", &source, || {
+        let mut tokenizer = Tokenizer::new(source.clone());
+        let mut count = 0;
+        while tokenizer.get_next_token().kind != TokenKind::EndOfFile {
+            count += 1;
+        }
+        count
+    });
+    println!("{} tokens", tokens);
+
+    bench("parse", &source, || {
+        let mut parser = Parser::new(source.clone());
+        parser.set_wrapped(true);
+        parser.parse();
+    });
+}
+
+/// Runs `f` once to warm up, then reports the mean time over `ITERATIONS`.
+fn bench<T>(name: &str, source: &str, mut f: impl FnMut() -> T) -> T {
+    let result = f();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let mean = start.elapsed() / ITERATIONS;
+
+    println!(
+        "{:<8}{:>10.2} ms{:>10.1} MB/s",
+        name,
+        mean.as_secs_f64() * 1000.0,
+        throughput(source.len(), mean)
+    );
+
+    result
+}
+
+fn throughput(bytes: usize, time: Duration) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0) / time.as_secs_f64()
+}
+
+fn generate_source(size: usize) -> String {
+    let mut source = String::from("(\n");
+    let mut index = 0;
+
+    while source.len() < size {
+        source.push_str(&format!(
+            "  ; function {index}\n  (def f{index} (a b) (begin\n    (var total{index} 0)\n    \
+             (while (<= total{index} 10.5) (begin (set total{index} (+ total{index} a))))\n    \
+             (if (>= total{index} b) \"greater\" \"lesser\")))\n  \
+             #| block #| nested |# comment |#\n  (var x{index} (call f{index} {index} 2))\n"
+        ));
+        index += 1;
+    }
+
+    source.push(')');
+    source
+}
@@ -0,0 +1,385 @@
+use std::{collections::HashMap, fmt, iter::Enumerate, str::Lines, sync::Arc};
+
+use crate::{
+    compiler::{CompileResult, LineInfo, LocalVar},
+    disassembler::op_code_name,
+    parser::reader::{DatumKind, Reader},
+    value::{FunctionObject, Value},
+    vm::*,
+};
+
+// The syntax is the one `disassembler::write_program` prints, one item per
+// line:
+//
+//   ; comment                        ignored, also after any other item
+//   .const <index> <value>           number, "string", true, false,
+//                                    'symbol, '(quoted list) or
+//   .const <index> .function <name> <arity> [<scope level>]
+//       ...                          the function's own constants and code
+//   .end
+//   .local <slot> <name> <start> <end>   debug name of a slot, hex addresses
+//   .line <line>                     the next instruction starts source <line>
+//   L1:                              label for the next instruction
+//   0004  01 02  CONST 2 (3)         address and byte columns are optional,
+//                                    `(...)` annotations are ignored
+//   .byte ff                         a raw byte
+//
+// Operands of JUMP, JUMP_IF_FALSE and TRY are labels or hex addresses,
+// other operands are decimal.
+
+/// Where and why the assembly source was rejected. `line` is 1-based.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+enum Operand {
+    Byte(u8),
+    // A jump target, two bytes once resolved.
+    Label { name: String, line: usize },
+}
+
+impl Operand {
+    fn size(&self) -> usize {
+        match self {
+            Operand::Byte(_) => 1,
+            Operand::Label { .. } => 2,
+        }
+    }
+}
+
+pub fn assemble(source: &str) -> Result<CompileResult, AssembleError> {
+    let mut lines = source.lines().enumerate();
+    let (result, end) = assemble_chunk(&mut lines)?;
+
+    if let Some(line) = end {
+        return Err(error(line, "`.end` without `.function`"));
+    }
+
+    Ok(result)
+}
+
+/// Assembles lines up to the end of the source or the next `.end`, whose
+/// line number is returned along with the chunk.
+fn assemble_chunk(
+    lines: &mut Enumerate<Lines<'_>>,
+) -> Result<(CompileResult, Option<usize>), AssembleError> {
+    let mut bytes: Vec<Operand> = vec![];
+    // Size of the bytecode assembled so far.
+    let mut address = 0;
+    let mut constants = vec![];
+    let mut locals = vec![];
+    let mut line_infos: Vec<LineInfo> = vec![];
+    let mut labels: HashMap<String, usize> = HashMap::new();
+
+    let mut end = None;
+    while let Some((index, text)) = lines.next() {
+        let line = index + 1;
+        let text = text.trim();
+
+        if let Some(rest) = text.strip_prefix(".const") {
+            let (index, constant) = assemble_constant(line, rest, lines)?;
+            if index != constants.len() {
+                return Err(error(
+                    line,
+                    &format!("Expected constant {}, found {}", constants.len(), index),
+                ));
+            }
+            constants.push(constant);
+            continue;
+        }
+
+        let mut tokens = strip_comment(text).split_whitespace().peekable();
+        let Some(&first) = tokens.peek() else {
+            continue;
+        };
+
+        match first {
+            ".end" => {
+                end = Some(line);
+                break;
+            }
+            ".local" => {
+                tokens.next();
+                let slot = parse_number(line, tokens.next())?;
+                let name = tokens
+                    .next()
+                    .ok_or_else(|| error(line, "`.local` needs a name"))?;
+                let start = parse_address(line, tokens.next())?;
+                let end = parse_address(line, tokens.next())?;
+                locals.push(LocalVar {
+                    name: name.to_string(),
+                    slot,
+                    start,
+                    end,
+                });
+                continue;
+            }
+            ".line" => {
+                tokens.next();
+                let source_line = tokens
+                    .next()
+                    .and_then(|token| token.parse().ok())
+                    .ok_or_else(|| error(line, "`.line` needs a line number"))?;
+                line_infos.push(LineInfo {
+                    address,
+                    line: source_line,
+                });
+                continue;
+            }
+            _ => {}
+        }
+
+        if let Some(label) = first.strip_suffix(':') {
+            tokens.next();
+            if labels.insert(label.to_string(), address).is_some() {
+                return Err(error(line, &format!("Duplicate label {}", label)));
+            }
+        }
+
+        // Skip the columns of disassembler output: a four digit address,
+        // then the instruction's bytes, two digits each.
+        let mut columns = tokens.clone();
+        if columns.next().is_some_and(|token| is_hex(token, 4))
+            && columns.next().is_some_and(|token| is_hex(token, 2))
+        {
+            tokens.nth(1);
+            while tokens.peek().is_some_and(|token| is_hex(token, 2)) {
+                tokens.next();
+            }
+            if tokens.peek().is_none() {
+                return Err(error(line, "Expected an instruction after the bytes"));
+            }
+        }
+
+        let Some(mnemonic) = tokens.next() else {
+            continue;
+        };
+        if mnemonic != ".byte" && op_code(mnemonic).is_none() {
+            return Err(error(line, &format!("Unknown instruction {}", mnemonic)));
+        }
+
+        if mnemonic == ".byte" {
+            let byte = tokens
+                .next()
+                .and_then(|token| u8::from_str_radix(token, 16).ok())
+                .ok_or_else(|| error(line, "`.byte` needs a hex byte"))?;
+            bytes.push(Operand::Byte(byte));
+            address += 1;
+            continue;
+        }
+
+        let op_code = op_code(mnemonic).unwrap();
+        bytes.push(Operand::Byte(op_code));
+        address += 1;
+
+        let operands = if is_jump(op_code) {
+            1
+        } else {
+            operand_count(op_code).unwrap()
+        };
+        for _ in 0..operands {
+            // Annotations like `(x)` may sit between operands.
+            while tokens.peek().is_some_and(|token| token.starts_with('(')) {
+                for token in tokens.by_ref() {
+                    if token.ends_with(')') {
+                        break;
+                    }
+                }
+            }
+
+            let Some(token) = tokens.next() else {
+                return Err(error(line, &format!("{} is missing an operand", mnemonic)));
+            };
+
+            let operand = if is_jump(op_code) {
+                Operand::Label {
+                    name: token.to_string(),
+                    line,
+                }
+            } else {
+                Operand::Byte(parse_number(line, Some(token))?)
+            };
+            address += operand.size();
+            bytes.push(operand);
+        }
+    }
+
+    // A label after the last instruction marks the end of the bytecode.
+    let mut bytecode = vec![];
+    for byte in bytes {
+        match byte {
+            Operand::Byte(byte) => bytecode.push(byte),
+            Operand::Label { name, line } => {
+                let address = match labels.get(&name) {
+                    Some(&address) => address,
+                    None => usize::from_str_radix(&name, 16)
+                        .map_err(|_| error(line, &format!("Unknown label {}", name)))?,
+                };
+                let operand = encode_address(address)
+                    .ok_or_else(|| error(line, &format!("Jump target {} is too far", name)))?;
+                bytecode.extend(operand);
+            }
+        }
+    }
+
+    Ok((
+        CompileResult {
+            bytecode,
+            constants,
+            vars: vec![],
+            locals,
+            lines: line_infos,
+        },
+        end,
+    ))
+}
+
+fn assemble_constant(
+    line: usize,
+    rest: &str,
+    lines: &mut Enumerate<Lines<'_>>,
+) -> Result<(usize, Value), AssembleError> {
+    let (index, value) = rest
+        .trim_start()
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| error(line, "`.const` needs an index and a value"))?;
+    let index = index
+        .parse()
+        .map_err(|_| error(line, &format!("Invalid constant index {}", index)))?;
+    let value = value.trim_start();
+
+    if let Some(function) = value.strip_prefix(".function") {
+        let mut tokens = strip_comment(function).split_whitespace();
+        let name = tokens
+            .next()
+            .ok_or_else(|| error(line, "`.function` needs a name"))?;
+        let arity = parse_number(line, tokens.next())?;
+        let scope_level = match tokens.next() {
+            Some(token) => parse_number(line, Some(token))?,
+            None => 0,
+        };
+
+        let (chunk, end) = assemble_chunk(lines)?;
+        if end.is_none() {
+            return Err(error(line, &format!("Function {} has no `.end`", name)));
+        }
+
+        let function = Value::Function {
+            val: Arc::new(FunctionObject {
+                name: name.to_string(),
+                scope_level,
+                arity,
+                bytecode: chunk.bytecode,
+                constants: chunk.constants,
+                locals: chunk.locals,
+                lines: chunk.lines,
+            }),
+        };
+        return Ok((index, function));
+    }
+
+    // Symbols and lists are written as quoted data: 'name or '(1 "a" b).
+    if value.starts_with('\'') {
+        let datum = Reader::new(value.to_string())
+            .try_read()
+            .map_err(|message| error(line, &format!("Invalid constant {}: {}", value, message)))?;
+        let DatumKind::List(items) = datum.kind else {
+            unreachable!("the reader expands 'x to (quote x)");
+        };
+        return Ok((index, Value::from(&items[1])));
+    }
+
+    if value.starts_with('"') {
+        let val = parse_string(value).map_err(|message| error(line, &message))?;
+        return Ok((index, Value::String { val }));
+    }
+
+    let constant = match strip_comment(value).trim() {
+        "true" => Ok(Value::Boolean { val: true }),
+        "false" => Ok(Value::Boolean { val: false }),
+        number => number
+            .parse()
+            .map(|val| Value::Number { val })
+            .map_err(|_| error(line, &format!("Invalid constant {}", number))),
+    }?;
+
+    Ok((index, constant))
+}
+
+/// Reads a double quoted string with Rust style escapes, as printed by
+/// `{:?}`. Anything after the closing quote is ignored.
+fn parse_string(text: &str) -> Result<String, String> {
+    let mut chars = text.chars().skip(1);
+    let mut string = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Ok(string),
+            '\\' => string.push(match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                Some('\'') => '\'',
+                Some('u') => {
+                    let code: String = chars
+                        .by_ref()
+                        .skip_while(|&c| c == '{')
+                        .take_while(|&c| c != '}')
+                        .collect();
+                    u32::from_str_radix(&code, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("Invalid escape \\u{{{}}}", code))?
+                }
+                Some(c) => return Err(format!("Invalid escape \\{}", c)),
+                None => break,
+            }),
+            c => string.push(c),
+        }
+    }
+
+    Err(String::from("Unterminated string"))
+}
+
+fn op_code(mnemonic: &str) -> Option<u8> {
+    (0..=u8::MAX)
+        .find(|&op_code| operand_count(op_code).is_some() && op_code_name(op_code) == mnemonic)
+}
+
+fn is_hex(token: &str, digits: usize) -> bool {
+    token.len() == digits && token.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn strip_comment(text: &str) -> &str {
+    text.split_once(';').map_or(text, |(code, _)| code)
+}
+
+fn parse_number(line: usize, token: Option<&str>) -> Result<u8, AssembleError> {
+    let token = token.ok_or_else(|| error(line, "Missing operand"))?;
+    token
+        .parse()
+        .map_err(|_| error(line, &format!("Invalid operand {}", token)))
+}
+
+fn parse_address(line: usize, token: Option<&str>) -> Result<usize, AssembleError> {
+    let token = token.ok_or_else(|| error(line, "Missing address"))?;
+    usize::from_str_radix(token, 16).map_err(|_| error(line, &format!("Invalid address {}", token)))
+}
+
+fn error(line: usize, message: &str) -> AssembleError {
+    AssembleError {
+        line,
+        message: message.to_string(),
+    }
+}
@@ -0,0 +1,292 @@
+use std::io::{self, Read, Write};
+
+use crate::{
+    value::{function, list, FunctionObject, Symbol, Value},
+    verifier,
+};
+
+use super::{CompileResult, LineInfo, LocalVar};
+
+// Layout of a `.vmc` file (all integers little endian):
+//
+//   magic     4 bytes  "VMC\0"
+//   version   u16
+//   chunk     the top level CompileResult
+//
+// A chunk is:
+//
+//   constants  u32 count, then one tagged entry per constant
+//   bytecode   u32 length, then the raw bytes
+//   locals     u32 count, then (string name, u8 slot, u32 start, u32 end)
+//              per entry
+//   lines      u32 count, then (u32 address, u32 line) per entry
+//
+// Constant entries start with a one byte tag followed by the payload:
+//
+//   0x00 number    f64
+//   0x01 string    string
+//   0x02 boolean   u8 (0 or 1)
+//   0x03 function  string name, u8 scope level, u8 arity, nested chunk
+//   0x04 symbol    string name
+//   0x05 list      u32 count, then one tagged entry per element
+//
+// Strings are a u32 byte length followed by UTF-8 bytes.
+
+const MAGIC: &[u8; 4] = b"VMC\0";
+const VERSION: u16 = 6;
+
+// Functions and lists in a file nest no deeper than this, so reading a
+// malformed file can't overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+const TAG_NUMBER: u8 = 0x00;
+const TAG_STRING: u8 = 0x01;
+const TAG_BOOLEAN: u8 = 0x02;
+const TAG_FUNCTION: u8 = 0x03;
+const TAG_SYMBOL: u8 = 0x04;
+const TAG_LIST: u8 = 0x05;
+
+impl CompileResult {
+    /// Serializes the bytecode, constant pool and debug info.
+    /// `vars` only matters while compiling and is not written.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+
+        write_chunk(
+            writer,
+            &self.constants,
+            &self.bytecode,
+            &self.locals,
+            &self.lines,
+        )
+    }
+
+    /// Reads a program written by `write_to`. Its bytecode is verified, so
+    /// whatever the file contains, the result is safe to run.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<CompileResult> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a bytecode file".to_string()));
+        }
+
+        let version = u16::from_le_bytes(read_array(reader)?);
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported bytecode version {} (expected {})",
+                version, VERSION
+            )));
+        }
+
+        let result = read_chunk(reader, 0)?;
+        verifier::verify(&result)
+            .map_err(|err| invalid_data(format!("Invalid bytecode: {}", err)))?;
+        Ok(result)
+    }
+}
+
+fn write_chunk<W: Write>(
+    writer: &mut W,
+    constants: &[Value],
+    bytecode: &[u8],
+    locals: &[LocalVar],
+    lines: &[LineInfo],
+) -> io::Result<()> {
+    write_len(writer, constants.len())?;
+    for constant in constants {
+        write_constant(writer, constant)?;
+    }
+
+    write_len(writer, bytecode.len())?;
+    writer.write_all(bytecode)?;
+
+    write_len(writer, locals.len())?;
+    for local in locals {
+        write_string(writer, &local.name)?;
+        writer.write_all(&[local.slot])?;
+        write_len(writer, local.start)?;
+        write_len(writer, local.end)?;
+    }
+
+    write_len(writer, lines.len())?;
+    for line in lines {
+        write_len(writer, line.address)?;
+        write_len(writer, line.line)?;
+    }
+
+    Ok(())
+}
+
+fn write_constant<W: Write>(writer: &mut W, constant: &Value) -> io::Result<()> {
+    match constant {
+        Value::Number { val } => {
+            writer.write_all(&[TAG_NUMBER])?;
+            writer.write_all(&val.to_le_bytes())
+        }
+        Value::String { val } => {
+            writer.write_all(&[TAG_STRING])?;
+            write_string(writer, val)
+        }
+        Value::Boolean { val } => writer.write_all(&[TAG_BOOLEAN, *val as u8]),
+        Value::Symbol { val } => {
+            writer.write_all(&[TAG_SYMBOL])?;
+            write_string(writer, &val.name())
+        }
+        Value::List { val } => {
+            writer.write_all(&[TAG_LIST])?;
+            write_len(writer, val.len())?;
+            for element in val.iter() {
+                write_constant(writer, element)?;
+            }
+            Ok(())
+        }
+        Value::Function { val } => {
+            writer.write_all(&[TAG_FUNCTION])?;
+            write_string(writer, &val.name)?;
+            writer.write_all(&[val.scope_level, val.arity])?;
+            write_chunk(
+                writer,
+                &val.constants,
+                &val.bytecode,
+                &val.locals,
+                &val.lines,
+            )
+        }
+    }
+}
+
+fn write_string<W: Write>(writer: &mut W, string: &str) -> io::Result<()> {
+    write_len(writer, string.len())?;
+    writer.write_all(string.as_bytes())
+}
+
+fn write_len<W: Write>(writer: &mut W, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len).map_err(|_| invalid_data("Section too large".to_string()))?;
+    writer.write_all(&len.to_le_bytes())
+}
+
+fn read_chunk<R: Read>(reader: &mut R, depth: usize) -> io::Result<CompileResult> {
+    let constants_len = read_len(reader)?;
+    let mut constants = Vec::with_capacity(constants_len.min(256));
+    for _ in 0..constants_len {
+        constants.push(read_constant(reader, depth + 1)?);
+    }
+
+    let bytecode = read_bytes(reader)?;
+
+    let locals_len = read_len(reader)?;
+    let mut locals = Vec::with_capacity(locals_len.min(256));
+    for _ in 0..locals_len {
+        let name = read_string(reader)?;
+        let [slot] = read_array(reader)?;
+        let start = read_len(reader)?;
+        let end = read_len(reader)?;
+        locals.push(LocalVar {
+            name,
+            slot,
+            start,
+            end,
+        });
+    }
+
+    let lines_len = read_len(reader)?;
+    let mut lines = Vec::with_capacity(lines_len.min(256));
+    for _ in 0..lines_len {
+        let address = read_len(reader)?;
+        let line = read_len(reader)?;
+        lines.push(LineInfo { address, line });
+    }
+
+    Ok(CompileResult {
+        bytecode,
+        constants,
+        vars: vec![],
+        locals,
+        lines,
+    })
+}
+
+fn read_constant<R: Read>(reader: &mut R, depth: usize) -> io::Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(invalid_data(format!(
+            "Constants nested deeper than {}",
+            MAX_DEPTH
+        )));
+    }
+
+    let [tag] = read_array(reader)?;
+
+    match tag {
+        TAG_NUMBER => Ok(Value::Number {
+            val: f64::from_le_bytes(read_array(reader)?),
+        }),
+        TAG_STRING => Ok(Value::String {
+            val: read_string(reader)?,
+        }),
+        TAG_BOOLEAN => match read_array(reader)? {
+            [0] => Ok(Value::Boolean { val: false }),
+            [1] => Ok(Value::Boolean { val: true }),
+            [byte] => Err(invalid_data(format!("Invalid boolean {}", byte))),
+        },
+        TAG_FUNCTION => {
+            let name = read_string(reader)?;
+            let [scope_level, arity] = read_array(reader)?;
+            let chunk = read_chunk(reader, depth)?;
+
+            Ok(function(FunctionObject {
+                name,
+                scope_level,
+                arity,
+                bytecode: chunk.bytecode,
+                constants: chunk.constants,
+                locals: chunk.locals,
+                lines: chunk.lines,
+            }))
+        }
+        TAG_SYMBOL => Ok(Value::Symbol {
+            val: Symbol::intern(&read_string(reader)?),
+        }),
+        TAG_LIST => {
+            let len = read_len(reader)?;
+            let mut elements = Vec::with_capacity(len.min(256));
+            for _ in 0..len {
+                elements.push(read_constant(reader, depth + 1)?);
+            }
+            Ok(list(elements))
+        }
+        _ => Err(invalid_data(format!("Unknown constant tag {}", tag))),
+    }
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(reader)?)
+        .map_err(|_| invalid_data("Invalid UTF-8 in string".to_string()))
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_len(reader)?;
+    let mut bytes = vec![];
+    // `take` keeps a corrupt length from allocating more than the file holds.
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+
+    Ok(bytes)
+}
+
+fn read_len<R: Read>(reader: &mut R) -> io::Result<usize> {
+    Ok(u32::from_le_bytes(read_array(reader)?) as usize)
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,443 @@
+use crate::{
+    parser::{AstNode, Pattern},
+    value::{list, number, string, symbol, Value},
+    vm::*,
+};
+
+use super::Compiler;
+
+// `match` is compiled into a decision tree: the clauses form a matrix with
+// one row per clause and one column per value still to test. A test on a
+// column keeps the rows that agree with its outcome, so each part of the
+// value is looked at once on the way to the first clause that matches.
+
+/// A clause still in the running. `patterns` has one entry per column;
+/// `bindings` are the names bound so far and the slots of their values.
+#[derive(Clone)]
+struct Row {
+    patterns: Vec<Pattern>,
+    bindings: Vec<(String, u8)>,
+    guard: Option<AstNode>,
+    body: AstNode,
+}
+
+/// An outcome a column is tested for.
+#[derive(Clone, PartialEq)]
+enum Constructor {
+    Literal(Value),
+    // A list of exactly this length.
+    Length(usize),
+    // A list of at least this length, none of the exact ones tested before.
+    AtLeast(usize),
+}
+
+/// A match being compiled.
+struct Match {
+    // Stack depth before the match, only the result is left above it.
+    base: usize,
+    // Jumps to the end, one after each clause body.
+    exits: Vec<usize>,
+    fallbacks: Vec<Fallback>,
+}
+
+/// Rows shared by the branches of a test, compiled once after them.
+/// Branches that run out of rows drop their variables down to `depth` and
+/// jump there.
+struct Fallback {
+    depth: usize,
+    jumps: Vec<usize>,
+}
+
+impl Compiler {
+    pub(super) fn match_expression(&mut self, node: AstNode) {
+        if let AstNode::MatchExpression { value, clauses } = node {
+            let mut state = Match {
+                base: self.depth(),
+                exits: vec![],
+                fallbacks: vec![],
+            };
+            let first_var = self.result.vars.len();
+
+            self.expression(*value);
+            let slot = self.hidden_var();
+
+            let rows = clauses
+                .into_iter()
+                .map(|clause| Row {
+                    patterns: vec![clause.pattern],
+                    bindings: vec![],
+                    guard: clause.guard,
+                    body: clause.body,
+                })
+                .collect();
+            self.decision_tree(rows, vec![slot], &mut state, None);
+
+            self.result.vars.truncate(first_var);
+            self.close_locals(self.depth());
+            self.patch_jumps(&state.exits);
+        }
+    }
+
+    /// Code that never falls through: every path ends in a clause body
+    /// jumping to the end, in the `fallback` or in a `match-error`. The
+    /// variables declared on the way are forgotten afterwards.
+    fn decision_tree(
+        &mut self,
+        rows: Vec<Row>,
+        columns: Vec<u8>,
+        state: &mut Match,
+        fallback: Option<usize>,
+    ) {
+        if rows.is_empty() {
+            match fallback {
+                Some(index) => {
+                    for _ in state.fallbacks[index].depth..self.depth() {
+                        self.emit(OP_POP);
+                    }
+                    let jump = self.emit_jump(OP_JUMP);
+                    state.fallbacks[index].jumps.push(jump);
+                }
+                None => {
+                    self.constant(list(vec![
+                        symbol("match-error"),
+                        string(String::from("No pattern matched")),
+                    ]));
+                    self.emit(OP_THROW);
+                }
+            }
+            return;
+        }
+
+        // Names match anything, they only bind the column's value.
+        let rows: Vec<Row> = rows
+            .into_iter()
+            .map(|mut row| {
+                for (pattern, &slot) in row.patterns.iter_mut().zip(&columns) {
+                    if let Pattern::Variable(name) = pattern {
+                        row.bindings.push((name.clone(), slot));
+                        *pattern = Pattern::Wildcard;
+                    }
+                }
+                row
+            })
+            .collect();
+
+        let column = rows[0]
+            .patterns
+            .iter()
+            .position(|pattern| !matches!(pattern, Pattern::Wildcard));
+        let first_var = self.result.vars.len();
+        match column {
+            Some(column) => self.switch(rows, columns, column, state, fallback),
+            None => self.leaf(rows, columns, state, fallback),
+        }
+        self.result.vars.truncate(first_var);
+        self.close_locals(self.depth());
+    }
+
+    /// The first row matches. Without a guard, or if it holds, its body
+    /// runs; otherwise the other rows are tried.
+    fn leaf(
+        &mut self,
+        rows: Vec<Row>,
+        columns: Vec<u8>,
+        state: &mut Match,
+        fallback: Option<usize>,
+    ) {
+        let mut rows = rows.into_iter();
+        let row = rows.next().unwrap();
+
+        let first_var = self.result.vars.len();
+        for (name, slot) in row.bindings {
+            self.emit(OP_GET_VAR);
+            self.emit(slot);
+            self.add_var(name);
+        }
+
+        let Some(guard) = row.guard else {
+            self.exit_match(row.body, state);
+            return;
+        };
+
+        self.expression(guard);
+        let guard_failed = self.emit_jump(OP_JUMP_IF_FALSE);
+        self.exit_match(row.body, state);
+
+        // The values stay on the stack, the names are only for this clause.
+        self.patch_jumps(&[guard_failed]);
+        if let Some(var) = self.result.vars.get(first_var) {
+            self.close_locals(var.slot as usize);
+        }
+        for var in &mut self.result.vars[first_var..] {
+            var.name = String::new();
+        }
+        self.decision_tree(rows.collect(), columns, state, fallback);
+    }
+
+    fn exit_match(&mut self, body: AstNode, state: &mut Match) {
+        self.expression(body);
+
+        self.emit_scope_exit(self.depth() - state.base);
+        let jump = self.emit_jump(OP_JUMP);
+        state.exits.push(jump);
+    }
+
+    /// Tests `column` for each constructor its patterns use, then falls back
+    /// to the rows that don't care about it. The rows after the last one
+    /// that does are the same for every outcome, so they become a fallback
+    /// instead of being copied into each branch.
+    fn switch(
+        &mut self,
+        mut rows: Vec<Row>,
+        columns: Vec<u8>,
+        column: usize,
+        state: &mut Match,
+        fallback: Option<usize>,
+    ) {
+        let last = rows
+            .iter()
+            .rposition(|row| !matches!(row.patterns[column], Pattern::Wildcard))
+            .unwrap();
+        let shared = rows.split_off(last + 1);
+        let outer_fallback = fallback;
+        let fallback = if shared.is_empty() {
+            fallback
+        } else {
+            state.fallbacks.push(Fallback {
+                depth: self.depth(),
+                jumps: vec![],
+            });
+            Some(state.fallbacks.len() - 1)
+        };
+
+        let slot = columns[column];
+        let mut other_columns = columns.clone();
+        other_columns.remove(column);
+
+        let (literals, lists): (Vec<Constructor>, Vec<Constructor>) = constructors(&rows, column)
+            .into_iter()
+            .partition(|constructor| matches!(constructor, Constructor::Literal(_)));
+
+        for constructor in literals {
+            let Constructor::Literal(value) = &constructor else {
+                unreachable!();
+            };
+            self.emit(OP_GET_VAR);
+            self.emit(slot);
+            self.constant(value.clone());
+            self.emit(OP_EQ);
+            let next = self.emit_jump(OP_JUMP_IF_FALSE);
+
+            let specialized = rows
+                .iter()
+                .filter_map(|row| specialize_literal(row, column, value))
+                .collect();
+            self.decision_tree(specialized, other_columns.clone(), state, fallback);
+
+            self.patch_jumps(&[next]);
+        }
+
+        if !lists.is_empty() {
+            self.emit(OP_GET_VAR);
+            self.emit(slot);
+            self.emit(OP_TYPE_OF);
+            self.constant(symbol("list"));
+            self.emit(OP_EQ);
+            let not_list = self.emit_jump(OP_JUMP_IF_FALSE);
+
+            for constructor in lists {
+                let (length, test) = match constructor {
+                    Constructor::Length(length) => (length, OP_EQ),
+                    Constructor::AtLeast(length) => (length, OP_GTE),
+                    Constructor::Literal(_) => unreachable!(),
+                };
+                self.emit(OP_GET_VAR);
+                self.emit(slot);
+                self.emit(OP_LENGTH);
+                self.constant(number(length as f64));
+                self.emit(test);
+                let next = self.emit_jump(OP_JUMP_IF_FALSE);
+
+                let first_var = self.result.vars.len();
+                let (specialized, columns) =
+                    self.specialize_list(&rows, &columns, column, &constructor);
+                self.decision_tree(specialized, columns, state, fallback);
+                self.result.vars.truncate(first_var);
+                self.close_locals(self.depth());
+
+                self.patch_jumps(&[next]);
+            }
+
+            self.patch_jumps(&[not_list]);
+        }
+
+        let default = rows
+            .into_iter()
+            .filter(|row| matches!(row.patterns[column], Pattern::Wildcard))
+            .map(|mut row| {
+                row.patterns.remove(column);
+                row
+            })
+            .collect();
+        self.decision_tree(default, other_columns, state, fallback);
+
+        if !shared.is_empty() {
+            let jumps = state.fallbacks.pop().unwrap().jumps;
+            self.patch_jumps(&jumps);
+            self.decision_tree(shared, columns, state, outer_fallback);
+        }
+    }
+
+    /// The rows for a list that passed `constructor`'s test, with its
+    /// elements and the tails the rows' rest patterns take as new columns.
+    /// Columns no row looks at are left out, the others are loaded into
+    /// variables.
+    fn specialize_list(
+        &mut self,
+        rows: &[Row],
+        columns: &[u8],
+        column: usize,
+        constructor: &Constructor,
+    ) -> (Vec<Row>, Vec<u8>) {
+        let (width, exact) = match *constructor {
+            Constructor::Length(length) => (length, true),
+            Constructor::AtLeast(length) => (length, false),
+            Constructor::Literal(_) => unreachable!(),
+        };
+
+        let compatible: Vec<&Row> = rows
+            .iter()
+            .filter(|row| match &row.patterns[column] {
+                Pattern::Wildcard => true,
+                Pattern::List {
+                    elements,
+                    rest: None,
+                } => exact && elements.len() == width,
+                Pattern::List {
+                    elements,
+                    rest: Some(_),
+                } => elements.len() <= width,
+                _ => false,
+            })
+            .collect();
+
+        let mut tails: Vec<usize> = compatible
+            .iter()
+            .filter_map(|row| match &row.patterns[column] {
+                Pattern::List {
+                    elements,
+                    rest: Some(_),
+                } => Some(elements.len()),
+                _ => None,
+            })
+            .collect();
+        tails.sort_unstable();
+        tails.dedup();
+
+        let mut specialized: Vec<Row> = compatible
+            .into_iter()
+            .map(|row| {
+                let mut new = vec![Pattern::Wildcard; width + tails.len()];
+                if let Pattern::List { elements, rest } = &row.patterns[column] {
+                    for (index, element) in elements.iter().enumerate() {
+                        new[index] = element.clone();
+                    }
+                    if let Some(rest) = rest {
+                        let tail = tails
+                            .iter()
+                            .position(|&tail| tail == elements.len())
+                            .unwrap();
+                        new[width + tail] = rest.as_ref().clone();
+                    }
+                }
+
+                let mut row = row.clone();
+                row.patterns.remove(column);
+                row.patterns.splice(0..0, new);
+                row
+            })
+            .collect();
+
+        let mut new_columns = vec![];
+        for new_column in 0..width + tails.len() {
+            let index = new_columns.len();
+            let is_used = specialized
+                .iter()
+                .any(|row| !matches!(row.patterns[index], Pattern::Wildcard));
+            if !is_used {
+                for row in &mut specialized {
+                    row.patterns.remove(index);
+                }
+                continue;
+            }
+
+            self.emit(OP_GET_VAR);
+            self.emit(columns[column]);
+            if new_column < width {
+                self.constant(number(new_column as f64));
+                self.emit(OP_NTH);
+            } else {
+                self.constant(number(tails[new_column - width] as f64));
+                self.emit(OP_DROP);
+            }
+            new_columns.push(self.hidden_var());
+        }
+
+        let mut other_columns = columns.to_vec();
+        other_columns.remove(column);
+        new_columns.extend(other_columns);
+        (specialized, new_columns)
+    }
+}
+
+/// The outcomes the patterns in `column` distinguish, in the order they are
+/// tested: literals, exact list lengths, then the lengths rest patterns
+/// need, longest first.
+fn constructors(rows: &[Row], column: usize) -> Vec<Constructor> {
+    let mut constructors = vec![];
+    let mut lengths = vec![];
+    let mut tails = vec![];
+
+    for row in rows {
+        match &row.patterns[column] {
+            Pattern::Literal(value) => {
+                let constructor = Constructor::Literal(value.clone());
+                if !constructors.contains(&constructor) {
+                    constructors.push(constructor);
+                }
+            }
+            Pattern::List {
+                elements,
+                rest: None,
+            } => lengths.push(elements.len()),
+            Pattern::List {
+                elements,
+                rest: Some(_),
+            } => tails.push(elements.len()),
+            Pattern::Wildcard | Pattern::Variable(_) => {}
+        }
+    }
+
+    lengths.sort_unstable();
+    lengths.dedup();
+    tails.sort_unstable_by(|a, b| b.cmp(a));
+    tails.dedup();
+
+    constructors.extend(lengths.into_iter().map(Constructor::Length));
+    constructors.extend(tails.into_iter().map(Constructor::AtLeast));
+    constructors
+}
+
+fn specialize_literal(row: &Row, column: usize, value: &Value) -> Option<Row> {
+    let matches = match &row.patterns[column] {
+        Pattern::Wildcard => true,
+        Pattern::Literal(literal) => literal == value,
+        _ => false,
+    };
+
+    matches.then(|| {
+        let mut row = row.clone();
+        row.patterns.remove(column);
+        row
+    })
+}
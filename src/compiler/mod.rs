@@ -1,13 +1,49 @@
+use std::{mem, path::PathBuf, time::Instant};
+
 use crate::{
-    parser::{AstNode, BinaryExpressionType, LiteralType},
-    value::Value,
+    parser::{hidden_name, is_hidden_name, AstNode, BinaryExpressionType, LetType, LiteralType},
+    value::{function, FunctionObject, Value},
     vm::*,
 };
 
+mod bytecode_file;
+mod matching;
+mod modules;
+mod prelude;
+
+use modules::{Exports, Modules};
+
+// End address of a local whose scope has not been closed yet.
+const OPEN: usize = usize::MAX;
+
 #[derive(Debug, Clone)]
 pub struct Var {
     pub name: String,
     pub scope_level: u8,
+    // Stack slot relative to the frame base. Variables declared while
+    // operands are pending sit above those operands.
+    pub slot: u8,
+    // The functions of a `letrec` group, passed as extra trailing arguments
+    // whenever the function in this var is called, since functions can't
+    // see the scope they are declared in.
+    pub hidden_arguments: Vec<String>,
+}
+
+/// Debug info: `name` lives in stack `slot` for the instructions at
+/// `start..end`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalVar {
+    pub name: String,
+    pub slot: u8,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Marks `address` as the first instruction compiled from source `line`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineInfo {
+    pub address: usize,
+    pub line: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -15,13 +51,48 @@ pub struct CompileResult {
     pub bytecode: Vec<u8>,
     pub constants: Vec<Value>,
     pub vars: Vec<Var>,
-    pub disassembler_vars: Vec<Var>,
+    pub locals: Vec<LocalVar>,
+    pub lines: Vec<LineInfo>,
+}
+
+/// A loop being compiled. `depth` is the stack depth above the frame base
+/// when the loop starts; `break` and `continue` drop anything above it.
+struct Loop {
+    depth: usize,
+    // Number of enclosing `try`s outside the loop.
+    tries: usize,
+    // Operands of the jumps to patch once the targets are known.
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+/// A `try` being compiled. Leaving it with `break` or `continue` has to
+/// remove its `handlers` and run its `finally` code on the way out.
+struct Try {
+    handlers: usize,
+    finally: Option<AstNode>,
 }
 
 pub struct Compiler {
     pub result: CompileResult,
     scope_level: u8,
     is_debug: bool,
+    loops: Vec<Loop>,
+    tries: Vec<Try>,
+    // Operands on the stack above the variables, waiting for the expression
+    // being compiled to complete their instruction.
+    temporaries: usize,
+    // Where the imports of the file being compiled are looked up.
+    directory: PathBuf,
+    modules: Modules,
+    // Exports of the imported modules, by their `module/name` names.
+    imports: Exports,
+    // Whether names the program doesn't declare are looked up in the
+    // prelude.
+    has_prelude: bool,
+    // Limits for the code that runs while compiling imported modules.
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
 }
 
 impl Compiler {
@@ -31,19 +102,53 @@ impl Compiler {
                 bytecode: vec![],
                 constants: vec![],
                 vars: vec![],
-                disassembler_vars: vec![],
+                locals: vec![],
+                lines: vec![],
             },
             scope_level: 0,
             is_debug,
+            loops: vec![],
+            tries: vec![],
+            temporaries: 0,
+            directory: PathBuf::new(),
+            modules: Modules::default(),
+            imports: vec![],
+            has_prelude: true,
+            fuel: None,
+            deadline: None,
         }
     }
 
+    /// With the prelude disabled, its functions have to be declared by the
+    /// program like any other.
+    pub fn set_prelude(&mut self, has_prelude: bool) {
+        self.has_prelude = has_prelude;
+    }
+
+    /// Stops the macros and code of imported modules when they run too
+    /// long, like `VM::set_fuel` and `VM::set_deadline`. Each module gets
+    /// `fuel` instructions for its macros and as many for its code.
+    pub fn set_limits(&mut self, fuel: Option<u64>, deadline: Option<Instant>) {
+        self.fuel = fuel;
+        self.deadline = deadline;
+    }
+
     pub fn compile(&mut self, ast: AstNode) {
         if let AstNode::Program { children } = ast {
+            // The prelude's functions are the program's outermost
+            // variables, so the program can read, set and shadow them.
+            if self.has_prelude {
+                for (name, value) in prelude::exports() {
+                    self.constant(value.clone());
+                    self.add_var(name.clone());
+                }
+            }
+
             for expression in children {
                 self.expression(expression);
             }
             self.emit(OP_HALT);
+            self.close_locals(0);
         } else {
             panic!("Invalid AST");
         }
@@ -60,8 +165,15 @@ impl Compiler {
             AstNode::WhileExpression { .. } => {
                 self.while_expression(expression);
             }
-            AstNode::VariableDeclaration { .. } => {
-                self.variable_declaration(expression);
+            AstNode::VariableDeclaration { .. } | AstNode::FunctionDeclaration { .. } => {
+                // Outside a sequence nothing can read the variable, and its
+                // slot would sit among pending operands.
+                self.scope_enter();
+                self.declaration(expression);
+                let slot = self.result.vars.last().unwrap().slot;
+                self.emit(OP_GET_VAR);
+                self.emit(slot);
+                self.scope_exit();
             }
             AstNode::SetVariable { .. } => {
                 self.set_variable(expression);
@@ -72,8 +184,41 @@ impl Compiler {
             AstNode::Block { .. } => {
                 self.block_expression(expression);
             }
-            AstNode::FunctionDeclaration { .. } => {
-                self.function_declaration(expression);
+            AstNode::LetExpression { .. } => {
+                self.let_expression(expression);
+            }
+            AstNode::Break { value } => {
+                self.break_expression(value);
+            }
+            AstNode::Continue => {
+                self.continue_expression();
+            }
+            AstNode::Throw { value } => {
+                self.expression(*value);
+                self.emit(OP_THROW);
+            }
+            AstNode::TryExpression { .. } => {
+                self.try_expression(expression);
+            }
+            AstNode::MatchExpression { .. } => {
+                self.match_expression(expression);
+            }
+            AstNode::Import { path, name, line } => {
+                self.import(path, name, line);
+            }
+            AstNode::Export { line, .. } => {
+                panic!(
+                    "export is only allowed at the top level of a module on line {}",
+                    line
+                );
+            }
+            AstNode::TypeOfExpression { value } => {
+                self.expression(*value);
+                self.emit(OP_TYPE_OF);
+            }
+            AstNode::LengthExpression { list } => {
+                self.expression(*list);
+                self.emit(OP_LENGTH);
             }
             AstNode::Literal {
                 r#type: literal_type,
@@ -98,6 +243,18 @@ impl Compiler {
             AstNode::CallExpression { .. } => {
                 self.call_expression(expression);
             }
+            AstNode::Quote { datum } => {
+                self.constant(Value::from(&datum));
+            }
+            AstNode::ListExpression { elements } => {
+                self.list_operation(OP_LIST, elements);
+            }
+            AstNode::ConcatExpression { lists } => {
+                self.list_operation(OP_CONCAT, lists);
+            }
+            AstNode::Gensym => {
+                self.emit(OP_GENSYM);
+            }
             _ => {
                 panic!("Invalid AST node");
             }
@@ -110,43 +267,107 @@ impl Compiler {
             parameters,
         } = node
         {
-            self.identifier(*identifier.clone());
-
-            // add current function object to stack frame
-            self.emit(OP_CONST);
-            for i in 0..self.result.constants.len() {
-                match &self.result.constants[i] {
-                    Value::Function {
-                        name: constant_name,
-                        scope_level: constant_scope_level,
-                        ..
-                    } => {
-                        if let AstNode::Identifier { name } = &(*identifier) {
-                            if constant_name == name && constant_scope_level == &self.scope_level {
-                                self.emit(i as u8);
-                                break;
-                            }
-                        }
+            if let AstNode::Identifier { name } = identifier.as_ref() {
+                if self.lookup(name).is_none() {
+                    if let Some(index) = native_index(name) {
+                        self.native_call(index, parameters);
+                        return;
                     }
-                    _ => continue,
                 }
             }
-            self.emit(OP_PARAM);
-            self.emit(0);
 
-            for (index, param) in parameters.into_iter().enumerate() {
-                self.expression(param);
+            // The function object ends up in slot 0 of the callee's frame,
+            // followed by the arguments.
+            let hidden_arguments = match identifier.as_ref() {
+                AstNode::Identifier { name } => self
+                    .lookup(name)
+                    .map(|index| self.result.vars[index].hidden_arguments.clone())
+                    .unwrap_or_default(),
+                _ => vec![],
+            };
+            if hidden_arguments.is_empty() {
+                self.expression(*identifier);
+            } else if let AstNode::Identifier { name } = *identifier {
+                self.get_var(&name);
+            }
+            self.temporaries += 1;
 
-                self.emit(OP_PARAM);
-                // +1 because the first element of the stack frame
-                // is the function object
-                self.emit((index + 1) as u8);
+            let pending = 1 + parameters.len();
+            let number_of_arguments = parameters.len() + hidden_arguments.len();
+            for param in parameters {
+                self.expression(param);
+                self.temporaries += 1;
             }
+            for name in hidden_arguments {
+                self.get_var(&name);
+            }
+            self.temporaries -= pending;
+
             self.emit(OP_CALL);
+            self.emit(number_of_arguments as u8);
+        }
+    }
+
+    /// Natives take their arguments straight from the stack, without a
+    /// call frame.
+    fn native_call(&mut self, index: u8, parameters: Vec<AstNode>) {
+        let native = &NATIVES[index as usize];
+        if parameters.len() != native.arity {
+            panic!(
+                "{} expects {} arguments, got {}",
+                native.name,
+                native.arity,
+                parameters.len()
+            );
+        }
+
+        let count = parameters.len();
+        for param in parameters {
+            self.expression(param);
+            self.temporaries += 1;
+        }
+        self.temporaries -= count;
+
+        self.emit(OP_NATIVE);
+        self.emit(index);
+    }
+
+    /// A native used as a value, wrapped in a function that passes its
+    /// parameters on.
+    fn native_function(&self, index: u8) -> Value {
+        let native = &NATIVES[index as usize];
+        let mut bytecode: Vec<u8> = (1..=native.arity as u8)
+            .flat_map(|slot| [OP_GET_VAR, slot])
+            .collect();
+        bytecode.extend([OP_NATIVE, index, OP_RETURN]);
+
+        function(FunctionObject {
+            name: native.name.to_string(),
+            scope_level: self.scope_level,
+            arity: native.arity as u8,
+            bytecode,
+            constants: vec![],
+            locals: vec![],
+            lines: vec![],
+        })
+    }
+
+    /// Pushes every operand, then `op_code` with their count.
+    fn list_operation(&mut self, op_code: u8, operands: Vec<AstNode>) {
+        let count = u8::try_from(operands.len()).expect("Too many list elements");
+        for operand in operands {
+            self.expression(operand);
+            self.temporaries += 1;
         }
+        self.temporaries -= count as usize;
+
+        self.emit(op_code);
+        self.emit(count);
     }
 
-    fn function_declaration(&mut self, node: AstNode) {
+    /// Compiles a function and declares it in the current scope. Functions
+    /// of a `letrec` group take the whole group as `hidden_parameters`.
+    fn function_declaration(&mut self, node: AstNode, hidden_parameters: &[String]) {
         if let AstNode::FunctionDeclaration {
             identifier,
             parameters,
@@ -162,37 +383,52 @@ impl Compiler {
 
             let prev_compile_result = self.result.clone();
             let prev_scope_level = self.scope_level;
+            // Loops outside the function can't be left from inside it.
+            let prev_loops = mem::take(&mut self.loops);
+            let prev_tries = mem::take(&mut self.tries);
+            let prev_temporaries = mem::take(&mut self.temporaries);
             self.result = CompileResult {
                 bytecode: vec![],
                 constants: vec![],
                 vars: vec![],
-                disassembler_vars: vec![],
+                locals: vec![],
+                lines: vec![],
             };
             self.scope_level = 1;
 
             self.add_param(function_name.clone());
 
+            let arity = (parameters.len() + hidden_parameters.len()) as u8;
             for param in parameters {
                 if let AstNode::Identifier { name } = param {
                     self.add_param(name);
                 }
             }
+            for name in hidden_parameters {
+                self.add_param(name.clone());
+                self.set_hidden_arguments(hidden_parameters);
+            }
 
             self.scope_level = 0;
             self.block_expression(*body);
             self.emit(OP_RETURN);
+            self.close_locals(0);
 
-            let function_object = Value::Function {
+            let function_object = function(FunctionObject {
                 name: function_name.clone(),
                 scope_level: prev_scope_level,
+                arity,
                 bytecode: self.result.bytecode.clone(),
                 constants: self.result.constants.clone(),
-                vars: self.result.vars.clone(),
-                disassembler_vars: self.result.disassembler_vars.clone(),
-            };
+                locals: self.result.locals.clone(),
+                lines: self.result.lines.clone(),
+            });
 
             self.result = prev_compile_result;
             self.scope_level = prev_scope_level;
+            self.loops = prev_loops;
+            self.tries = prev_tries;
+            self.temporaries = prev_temporaries;
 
             self.constant(function_object);
 
@@ -201,54 +437,154 @@ impl Compiler {
     }
 
     fn block_expression(&mut self, node: AstNode) {
-        if let AstNode::Block { children } = node {
+        if let AstNode::Block { children, lines } = node {
             self.scope_enter();
+            self.sequence(children, lines);
+            self.scope_exit();
+        }
+    }
 
-            let children_len = children.len();
-
-            for (index, child) in children.into_iter().enumerate() {
-                let should_pop = match child {
-                    AstNode::VariableDeclaration { .. } => false,
-                    _ => true,
-                } && index != children_len - 1;
+    fn let_expression(&mut self, node: AstNode) {
+        if let AstNode::LetExpression {
+            r#type,
+            bindings,
+            body,
+            lines,
+        } = node
+        {
+            self.scope_enter();
 
-                self.expression(child);
+            let (functions, values): (Vec<AstNode>, Vec<AstNode>) = bindings
+                .into_iter()
+                .partition(|binding| matches!(binding, AstNode::FunctionDeclaration { .. }));
+
+            let group: Vec<String> = functions
+                .iter()
+                .filter_map(|function| match function {
+                    AstNode::FunctionDeclaration { identifier, .. } => match identifier.as_ref() {
+                        AstNode::Identifier { name } => Some(name.clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect();
+            for function in functions {
+                self.function_declaration(function, &group);
+                self.set_hidden_arguments(&group);
+            }
 
-                if should_pop {
-                    self.emit(OP_POP);
+            let first_var = self.result.vars.len();
+            let mut names = vec![];
+            for value in values {
+                if let AstNode::VariableDeclaration { identifier, value } = value {
+                    if let AstNode::Identifier { name } = *identifier {
+                        self.expression(*value);
+
+                        // `let` names its variables only once all values are
+                        // computed; until then the slots can't be looked up.
+                        if r#type == LetType::Let {
+                            self.add_var(String::new());
+                            names.push(name);
+                        } else {
+                            self.add_var(name);
+                        }
+                    }
                 }
             }
+            for (index, name) in (first_var..).zip(names) {
+                self.rename_var(index, name);
+            }
 
+            self.sequence(body, lines);
             self.scope_exit();
         }
     }
 
-    fn identifier(&mut self, node: AstNode) {
-        if let AstNode::Identifier { name } = node {
-            if self.result.vars.len() == 0 {
-                panic!("Variable: {} not found", name);
-            }
+    /// Compiles expressions in order, keeping the value of the last one.
+    fn sequence(&mut self, children: Vec<AstNode>, lines: Vec<usize>) {
+        let children_len = children.len();
 
-            self.emit(OP_GET_VAR);
+        for (index, (child, line)) in children.into_iter().zip(lines).enumerate() {
+            self.add_line(line);
 
-            for i in (0..self.result.vars.len()).rev() {
-                if self.result.vars[i].name == name {
-                    self.emit(i as u8);
+            let is_last = index == children_len - 1;
+            let vars_count = self.result.vars.len();
 
-                    if self.is_debug {
-                        self.result.disassembler_vars.push(Var {
-                            name,
-                            scope_level: self.scope_level,
-                        });
-                    }
+            self.declaration(child);
+
+            let is_declaration = self.result.vars.len() > vars_count;
+            if is_declaration && is_last {
+                // The declared value stays on the stack as the variable's
+                // slot, so the block result has to be a separate copy.
+                let slot = self.result.vars.last().unwrap().slot;
+                self.emit(OP_GET_VAR);
+                self.emit(slot);
+            } else if !is_declaration && !is_last {
+                self.emit(OP_POP);
+            }
+        }
+    }
+
+    /// Declarations add a variable to the enclosing scope, anything else is
+    /// compiled as an expression.
+    fn declaration(&mut self, node: AstNode) {
+        match node {
+            AstNode::VariableDeclaration { .. } => self.variable_declaration(node),
+            AstNode::FunctionDeclaration { .. } => self.function_declaration(node, &[]),
+            _ => self.expression(node),
+        }
+    }
+
+    fn identifier(&mut self, node: AstNode) {
+        if let AstNode::Identifier { name } = node {
+            let Some(index) = self.lookup(&name) else {
+                if let Some((_, value)) = self
+                    .imports
+                    .iter()
+                    .rev()
+                    .find(|(import, _)| *import == name)
+                {
+                    self.constant(value.clone());
                     return;
                 }
+                if let Some(index) = native_index(&name) {
+                    let function = self.native_function(index);
+                    self.constant(function);
+                    return;
+                }
+                // Functions don't see the program's variables, so they use
+                // the prelude's functions directly.
+                match self.has_prelude.then(|| prelude::lookup(&name)).flatten() {
+                    Some(value) => self.constant(value.clone()),
+                    None => panic!("Variable: {} not found", name),
+                }
+                return;
+            };
+            if !self.result.vars[index].hidden_arguments.is_empty() {
+                panic!("Function {} from letrec can only be called", name);
             }
 
-            panic!("Variable: {} not found", name);
+            self.get_var(&name);
         }
     }
 
+    fn get_var(&mut self, name: &str) {
+        let slot = self.result.vars[self.resolve(name)].slot;
+
+        self.emit(OP_GET_VAR);
+        self.emit(slot);
+    }
+
+    /// Index in `vars` of the innermost variable called `name`.
+    fn resolve(&self, name: &str) -> usize {
+        self.lookup(name)
+            .unwrap_or_else(|| panic!("Variable: {} not found", name))
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.result.vars.iter().rposition(|var| var.name == name)
+    }
+
     fn variable_declaration(&mut self, node: AstNode) {
         if let AstNode::VariableDeclaration { identifier, value } = node {
             if let AstNode::Identifier { name } = *identifier {
@@ -266,22 +602,9 @@ impl Compiler {
             if let AstNode::Identifier { name } = *identifier {
                 self.expression(*value);
 
+                let slot = self.result.vars[self.resolve(&name)].slot;
                 self.emit(OP_SET_VAR);
-                for i in (0..self.result.vars.len()).rev() {
-                    if self.result.vars[i].name == name {
-                        self.emit(i as u8);
-
-                        if self.is_debug {
-                            self.result.disassembler_vars.push(Var {
-                                name,
-                                scope_level: self.scope_level,
-                            });
-                        }
-                        return;
-                    }
-                }
-
-                panic!("Variable: {} not found", name);
+                self.emit(slot);
             }
         } else {
             panic!("Not a valid set operation");
@@ -297,40 +620,224 @@ impl Compiler {
         {
             self.expression(*condition);
 
-            self.emit(OP_JUMP_IF_FALSE);
-            self.emit(0);
-            let jump_if_false_address = self.result.bytecode.len() - 1;
+            let jump_if_false_address = self.emit_jump(OP_JUMP_IF_FALSE);
 
             self.expression(*consequent);
 
-            self.emit(OP_JUMP);
-            self.emit(0);
-            let jump_address = self.result.bytecode.len() - 1;
+            let jump_address = self.emit_jump(OP_JUMP);
 
-            self.result.bytecode[jump_if_false_address] = self.result.bytecode.len() as u8;
+            self.patch_jumps(&[jump_if_false_address]);
 
             self.expression(*alternate);
 
-            self.result.bytecode[jump_address] = self.result.bytecode.len() as u8;
+            self.patch_jumps(&[jump_address]);
         }
     }
 
     fn while_expression(&mut self, node: AstNode) {
-        if let AstNode::WhileExpression { condition, body } = node {
+        if let AstNode::WhileExpression {
+            condition,
+            body,
+            update,
+        } = node
+        {
             let loop_start_address = self.result.bytecode.len();
 
             self.expression(*condition);
 
-            self.emit(OP_JUMP_IF_FALSE);
-            self.emit(0);
-            let jump_if_false_address = self.result.bytecode.len() - 1;
+            let jump_if_false_address = self.emit_jump(OP_JUMP_IF_FALSE);
+
+            self.loops.push(Loop {
+                depth: self.depth(),
+                tries: self.tries.len(),
+                breaks: vec![],
+                continues: vec![],
+            });
 
             self.expression(*body);
+            self.emit(OP_POP);
+
+            let context = self.loops.pop().unwrap();
+            self.patch_jumps(&context.continues);
+
+            if let Some(update) = update {
+                self.expression(*update);
+                self.emit(OP_POP);
+            }
 
             self.emit(OP_JUMP);
-            self.emit(loop_start_address as u8);
+            self.emit_address(loop_start_address);
+
+            self.patch_jumps(&[jump_if_false_address]);
+
+            // Every expression leaves a value behind; a finished loop has none
+            // of its own.
+            self.constant(Value::Boolean { val: false });
+
+            self.patch_jumps(&context.breaks);
+        }
+    }
 
-            self.result.bytecode[jump_if_false_address] = self.result.bytecode.len() as u8;
+    fn break_expression(&mut self, value: Option<Box<AstNode>>) {
+        let Some((depth, tries)) = self
+            .loops
+            .last()
+            .map(|context| (context.depth, context.tries))
+        else {
+            panic!("break outside of a loop");
+        };
+
+        match value {
+            Some(value) => self.expression(*value),
+            None => self.constant(Value::Boolean { val: false }),
+        }
+
+        self.temporaries += 1;
+        self.leave_tries(tries);
+        self.temporaries -= 1;
+
+        // Variables of the scopes being left and pending operands go, the
+        // value stays.
+        self.emit_scope_exit(self.depth() - depth);
+
+        let address = self.emit_jump(OP_JUMP);
+        self.loops.last_mut().unwrap().breaks.push(address);
+    }
+
+    fn continue_expression(&mut self) {
+        let Some((depth, tries)) = self
+            .loops
+            .last()
+            .map(|context| (context.depth, context.tries))
+        else {
+            panic!("continue outside of a loop");
+        };
+
+        self.leave_tries(tries);
+
+        for _ in depth..self.depth() {
+            self.emit(OP_POP);
+        }
+
+        let address = self.emit_jump(OP_JUMP);
+        self.loops.last_mut().unwrap().continues.push(address);
+    }
+
+    /// Removes the handlers of the `try`s from the innermost one down to
+    /// `first` and runs their `finally` code, innermost first.
+    fn leave_tries(&mut self, first: usize) {
+        for index in (first..self.tries.len()).rev() {
+            for _ in 0..self.tries[index].handlers {
+                self.emit(OP_END_TRY);
+            }
+
+            if let Some(finally) = self.tries[index].finally.clone() {
+                // A jump out of the `finally` code leaves only the `try`s
+                // around this one.
+                let inner = self.tries.split_off(index);
+                self.expression(finally);
+                self.emit(OP_POP);
+                self.tries.extend(inner);
+            }
+        }
+    }
+
+    /// `TRY` pushes a handler that catches what is thrown until the
+    /// matching `END_TRY`. With both clauses:
+    ///
+    /// ```text
+    ///         TRY finally
+    ///         TRY catch
+    ///         body
+    ///         END_TRY
+    ///         JUMP done
+    /// catch:  handler, with the thrown value as its variable
+    /// done:   END_TRY
+    ///         cleanup, POP
+    ///         JUMP end
+    /// finally: cleanup, POP
+    ///         THROW
+    /// end:
+    /// ```
+    fn try_expression(&mut self, node: AstNode) {
+        if let AstNode::TryExpression {
+            body,
+            catch,
+            finally,
+        } = node
+        {
+            let finally = finally.map(|finally| *finally);
+
+            let finally_address = finally.as_ref().map(|_| self.emit_jump(OP_TRY));
+            let catch_address = catch.as_ref().map(|_| self.emit_jump(OP_TRY));
+            self.tries.push(Try {
+                handlers: finally_address.iter().count() + catch_address.iter().count(),
+                finally: finally.clone(),
+            });
+
+            self.expression(*body);
+
+            if let (Some(catch_address), Some((name, handler))) = (catch_address, catch) {
+                self.emit(OP_END_TRY);
+                self.tries.last_mut().unwrap().handlers -= 1;
+                let done_address = self.emit_jump(OP_JUMP);
+
+                // The handler starts at the depth of the `TRY`, with the
+                // thrown value on top.
+                self.patch_jumps(&[catch_address]);
+                self.scope_enter();
+                self.add_var(name);
+                self.expression(*handler);
+                self.scope_exit();
+
+                self.patch_jumps(&[done_address]);
+            }
+
+            self.tries.pop();
+
+            if let (Some(finally_address), Some(finally)) = (finally_address, finally) {
+                self.emit(OP_END_TRY);
+                self.cleanup(finally.clone());
+                let end_address = self.emit_jump(OP_JUMP);
+
+                // Runs the cleanup with the thrown value on the stack, then
+                // throws it on.
+                self.patch_jumps(&[finally_address]);
+                self.cleanup(finally);
+                self.emit(OP_THROW);
+
+                self.patch_jumps(&[end_address]);
+            }
+        }
+    }
+
+    /// `finally` code, run above the value that stays on the stack.
+    fn cleanup(&mut self, finally: AstNode) {
+        self.temporaries += 1;
+        self.expression(finally);
+        self.emit(OP_POP);
+        self.temporaries -= 1;
+    }
+
+    /// Emits `op_code` with an operand to patch, and returns its address.
+    fn emit_jump(&mut self, op_code: u8) -> usize {
+        self.emit(op_code);
+        let address = self.result.bytecode.len();
+        self.emit_address(0);
+        address
+    }
+
+    fn emit_address(&mut self, address: usize) {
+        for byte in address_operand(address) {
+            self.emit(byte);
+        }
+    }
+
+    /// Points the jump operands at `addresses` to the current address.
+    fn patch_jumps(&mut self, addresses: &[usize]) {
+        let target = address_operand(self.result.bytecode.len());
+        for &address in addresses {
+            self.result.bytecode[address..address + 2].copy_from_slice(&target);
         }
     }
 
@@ -342,7 +849,9 @@ impl Compiler {
         } = node
         {
             self.expression(*left);
+            self.temporaries += 1;
             self.expression(*right);
+            self.temporaries -= 1;
 
             match binary_expression_type {
                 BinaryExpressionType::Add => {
@@ -372,6 +881,12 @@ impl Compiler {
                 BinaryExpressionType::Equal => {
                     self.emit(OP_EQ);
                 }
+                BinaryExpressionType::Nth => {
+                    self.emit(OP_NTH);
+                }
+                BinaryExpressionType::Drop => {
+                    self.emit(OP_DROP);
+                }
             }
         }
     }
@@ -409,22 +924,12 @@ impl Compiler {
                         }
                     }
                 }
-                Value::Function {
-                    name: constant_name,
-                    scope_level: constant_scope_level,
-                    ..
-                } => {
-                    if let Value::Function {
-                        name: value_name,
-                        scope_level: value_scope_level,
-                        ..
-                    } = &value
-                    {
-                        if constant_name == value_name && constant_scope_level == value_scope_level
-                        {
-                            self.emit(i as u8);
-                            return;
-                        }
+                // Functions are compared whole, a prelude function and one
+                // the program declares may share a name.
+                constant @ (Value::Symbol { .. } | Value::List { .. } | Value::Function { .. }) => {
+                    if *constant == value {
+                        self.emit(i as u8);
+                        return;
                     }
                 }
             }
@@ -434,7 +939,7 @@ impl Compiler {
         self.result.constants.push(value);
     }
 
-    fn get_vars_count_on_scope_exit(&mut self) -> u8 {
+    fn get_vars_count_on_scope_exit(&mut self) -> usize {
         let mut count = 0;
 
         for i in (0..self.result.vars.len()).rev() {
@@ -452,33 +957,116 @@ impl Compiler {
     }
 
     fn add_param(&mut self, name: String) {
+        self.open_local(name.clone(), 0);
+
         self.result.vars.push(Var {
-            name: name.clone(),
+            name,
             scope_level: self.scope_level,
+            slot: self.next_slot(),
+            hidden_arguments: vec![],
         });
-
-        if self.is_debug {
-            self.result.disassembler_vars.push(Var {
-                name,
-                scope_level: self.scope_level,
-            });
-        }
     }
 
+    /// Declares the value on top of the stack as `name`.
     fn add_var(&mut self, name: String) {
+        self.open_local(name.clone(), self.result.bytecode.len());
+
+        let slot = self.next_slot();
         self.emit(OP_SET_VAR);
-        self.emit(self.result.vars.len() as u8);
+        self.emit(slot);
 
         self.result.vars.push(Var {
-            name: name.clone(),
+            name,
             scope_level: self.scope_level,
+            slot,
+            hidden_arguments: vec![],
         });
+    }
 
-        if self.is_debug {
-            self.result.disassembler_vars.push(Var {
-                name,
-                scope_level: self.scope_level,
-            });
+    /// Declares the value on top of the stack as a variable user code can't
+    /// name, returning its slot.
+    fn hidden_var(&mut self) -> u8 {
+        self.add_var(hidden_name());
+        self.result.vars.last().unwrap().slot
+    }
+
+    /// Number of values above the frame base: every variable and pending
+    /// operand.
+    fn depth(&self) -> usize {
+        self.result.vars.len() + self.temporaries
+    }
+
+    /// The slot the next variable goes into. Slots are one byte wide, like
+    /// constant indices.
+    fn next_slot(&self) -> u8 {
+        u8::try_from(self.depth()).unwrap_or_else(|_| {
+            panic!(
+                "Function too large: more than {} variables and pending operands",
+                u8::MAX
+            )
+        })
+    }
+
+    fn set_hidden_arguments(&mut self, group: &[String]) {
+        if let Some(var) = self.result.vars.last_mut() {
+            var.hidden_arguments = group.to_vec();
+        }
+    }
+
+    fn rename_var(&mut self, index: usize, name: String) {
+        let slot = self.result.vars[index].slot;
+        let is_open = |local: &LocalVar| local.slot == slot && local.end == OPEN;
+        if is_hidden_name(&name) {
+            self.result.locals.retain(|local| !is_open(local));
+        }
+        for local in self.result.locals.iter_mut().filter(|local| is_open(local)) {
+            local.name = name.clone();
+        }
+
+        self.result.vars[index].name = name;
+    }
+
+    /// Records that the next slot holds `name` from `start` on; the range is
+    /// closed by `close_locals` when the slot goes out of scope.
+    fn open_local(&mut self, name: String, start: usize) {
+        // Hidden variables have no names to show, and theirs wouldn't fit in
+        // a `.local` line.
+        if !self.is_debug || is_hidden_name(&name) {
+            return;
+        }
+
+        self.result.locals.push(LocalVar {
+            name,
+            slot: self.next_slot(),
+            start,
+            end: OPEN,
+        });
+    }
+
+    /// Ends the ranges of the open locals in `first_slot..` at the current
+    /// address.
+    fn close_locals(&mut self, first_slot: usize) {
+        let address = self.result.bytecode.len();
+
+        for local in self.result.locals.iter_mut() {
+            if local.end == OPEN && local.slot as usize >= first_slot {
+                local.end = address;
+            }
+        }
+    }
+
+    fn add_line(&mut self, line: usize) {
+        if !self.is_debug {
+            return;
+        }
+
+        let address = self.result.bytecode.len();
+        match self.result.lines.last_mut() {
+            Some(last) if last.line == line => {}
+            // Nothing was emitted for the previous line, e.g. a nested block
+            // starting on the same address.
+            Some(last) if last.address == address => last.line = line,
+            _ => self.result.lines.push(LineInfo { address, line }),
         }
     }
 
@@ -490,11 +1078,35 @@ impl Compiler {
         let vars_count = self.get_vars_count_on_scope_exit();
 
         self.scope_level -= 1;
-        self.emit(OP_SCOPE_EXIT);
-        self.emit(vars_count);
+        self.emit_scope_exit(vars_count);
+
+        // The variables are still readable by the SCOPE_EXIT itself.
+        self.close_locals(self.depth());
+    }
+
+    /// Drops `count` values from under the top of the stack. The operand is
+    /// a byte, so more than 255 take several instructions.
+    pub(super) fn emit_scope_exit(&mut self, mut count: usize) {
+        while count > 0 {
+            let chunk = count.min(u8::MAX as usize);
+            self.emit(OP_SCOPE_EXIT);
+            self.emit(chunk as u8);
+            count -= chunk;
+        }
     }
 
     fn emit(&mut self, byte: u8) {
         self.result.bytecode.push(byte);
     }
 }
+
+// Jump operands are 16 bits wide, which caps the size of a function.
+fn address_operand(address: usize) -> [u8; 2] {
+    encode_address(address).unwrap_or_else(|| {
+        panic!(
+            "Function too large: jump target {} is past the limit of {} bytes",
+            address,
+            u16::MAX
+        )
+    })
+}
@@ -0,0 +1,174 @@
+use std::{
+    collections::HashMap,
+    fs, mem,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    parser::{AstNode, Parser},
+    value::{function, FunctionObject, Value},
+    vm::*,
+};
+
+use super::{CompileResult, Compiler};
+
+/// The exported names of a module with their values.
+pub(super) type Exports = Vec<(String, Value)>;
+
+/// A compiled module. Its code runs like a function without parameters and
+/// returns the values of `exports` as a list.
+pub(super) struct Module {
+    pub result: CompileResult,
+    pub exports: Vec<String>,
+}
+
+impl Module {
+    /// Runs the module's code in `vm`, as the module called `name`.
+    pub fn evaluate(self, name: &str, vm: &mut VM) -> Result<Exports, RuntimeError> {
+        let function = function(FunctionObject {
+            name: name.to_string(),
+            scope_level: 0,
+            arity: 0,
+            bytecode: self.result.bytecode,
+            constants: self.result.constants,
+            locals: self.result.locals,
+            lines: self.result.lines,
+        });
+
+        let values = vm.exec(vec![function], vec![OP_CONST, 0, OP_CALL, 0, OP_HALT])?;
+        let Value::List { val: values } = values else {
+            unreachable!("modules return their exports as a list");
+        };
+
+        Ok(self
+            .exports
+            .into_iter()
+            .zip(values.iter().cloned())
+            .collect())
+    }
+}
+
+/// The modules of a program, each compiled and run once however often it
+/// is imported.
+#[derive(Default)]
+pub(super) struct Modules {
+    evaluated: HashMap<PathBuf, Exports>,
+    // The files being compiled, each one importing the next.
+    loading: Vec<PathBuf>,
+}
+
+impl Compiler {
+    /// Imports are resolved relative to the directory of `path`, the file
+    /// being compiled.
+    pub fn set_path(&mut self, path: &Path) {
+        self.directory = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.modules.loading = fs::canonicalize(path).into_iter().collect();
+    }
+
+    /// Makes each export of the module available as `name/export`. The
+    /// module runs while compiling, so like prelude functions its exports
+    /// become constants that functions can use too.
+    pub(super) fn import(&mut self, path: String, name: String, line: usize) {
+        let file = fs::canonicalize(self.directory.join(&path))
+            .unwrap_or_else(|err| panic!("Can't import {}: {} on line {}", path, err, line));
+
+        if let Some(index) = self.modules.loading.iter().position(|other| *other == file) {
+            let cycle: Vec<String> = self.modules.loading[index..]
+                .iter()
+                .chain([&file])
+                .map(|path| path.display().to_string())
+                .collect();
+            panic!("Import cycle {} on line {}", cycle.join(" -> "), line);
+        }
+
+        let exports = match self.modules.evaluated.get(&file) {
+            Some(exports) => exports.clone(),
+            None => {
+                let exports = self.evaluate_module(&file, &path, line);
+                self.modules.evaluated.insert(file, exports.clone());
+                exports
+            }
+        };
+
+        for (export, value) in exports {
+            self.imports.push((format!("{}/{}", name, export), value));
+        }
+
+        // Every expression leaves a value behind.
+        self.constant(Value::Boolean { val: false });
+    }
+
+    fn evaluate_module(&mut self, file: &Path, path: &str, line: usize) -> Exports {
+        let source = fs::read_to_string(file)
+            .unwrap_or_else(|err| panic!("Can't import {}: {} on line {}", path, err, line));
+
+        self.modules.loading.push(file.to_path_buf());
+        let directory = file.parent().map(Path::to_path_buf).unwrap_or_default();
+        let module = self.module(source, path, directory);
+        self.modules.loading.pop();
+
+        let mut vm = VM::new();
+        vm.set_fuel(self.fuel);
+        vm.set_deadline(self.deadline);
+        module
+            .evaluate(path, &mut vm)
+            .unwrap_or_else(|err| panic!("Module {} failed: {} on line {}", path, err, line))
+    }
+
+    /// Compiles `source` like a function body that ends in the list of the
+    /// exported values. Its imports are looked up in `directory`.
+    pub(super) fn module(&mut self, source: String, name: &str, directory: PathBuf) -> Module {
+        let mut parser = Parser::new(source);
+        parser.set_limits(self.fuel, self.deadline);
+        let AstNode::Program { mut children } = parser.parse() else {
+            unreachable!();
+        };
+        let Some(AstNode::Block { children, lines }) = children.pop() else {
+            unreachable!();
+        };
+
+        let mut exports = vec![];
+        let mut body = vec![];
+        let mut body_lines = vec![];
+        for (child, line) in children.into_iter().zip(lines) {
+            match child {
+                AstNode::Export { names, .. } => exports.extend(names),
+                child => {
+                    body.push(child);
+                    body_lines.push(line);
+                }
+            }
+        }
+        body.push(AstNode::ListExpression {
+            elements: exports
+                .iter()
+                .map(|name| AstNode::Identifier { name: name.clone() })
+                .collect(),
+        });
+        body_lines.push(body_lines.last().copied().unwrap_or(1));
+
+        let mut compiler = Compiler::new(self.is_debug);
+        compiler.directory = directory;
+        compiler.has_prelude = self.has_prelude;
+        compiler.set_limits(self.fuel, self.deadline);
+        compiler.modules = mem::take(&mut self.modules);
+
+        // Slot 0 holds the module's code, like a function's.
+        compiler.scope_level = 1;
+        compiler.add_param(name.to_string());
+        compiler.scope_level = 0;
+        compiler.block_expression(AstNode::Block {
+            children: body,
+            lines: body_lines,
+        });
+        compiler.emit(OP_RETURN);
+        compiler.close_locals(0);
+
+        self.modules = mem::take(&mut compiler.modules);
+
+        Module {
+            result: compiler.result,
+            exports,
+        }
+    }
+}
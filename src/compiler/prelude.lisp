@@ -0,0 +1,50 @@
+; Functions every program can use without importing them. Functions can't
+; see each other, so each one stands on its own.
+;
+; Appending to a list copies it, so the functions that build lists split
+; their input in halves and only append element by element to short runs.
+; That keeps them at n log n instead of quadratic.
+
+(def map (f xs)
+  (def go (f xs start end)
+    (if (<= (- end start) 16)
+        (begin
+          (var result '())
+          (for (i start end) (set result `(,@result ,(f (nth xs i)))))
+          result)
+        (begin
+          (var middle (floor (/ (+ start end) 2)))
+          `(,@(go f xs start middle) ,@(go f xs middle end)))))
+  (go f xs 0 (length xs)))
+
+(def filter (f xs)
+  (def go (f xs start end)
+    (if (<= (- end start) 16)
+        (begin
+          (var result '())
+          (for (i start end)
+            (let ((x (nth xs i)))
+              (when (f x) (set result `(,@result ,x)))))
+          result)
+        (begin
+          (var middle (floor (/ (+ start end) 2)))
+          `(,@(go f xs start middle) ,@(go f xs middle end)))))
+  (go f xs 0 (length xs)))
+
+(def reduce (f initial xs)
+  (var result initial)
+  (for-each x xs (set result (f result x)))
+  result)
+
+; The numbers from start up to end, exclusive.
+(def range (start end)
+  (if (<= (- end start) 16)
+      (begin
+        (var result '())
+        (for (i start end) (set result `(,@result ,i)))
+        result)
+      (begin
+        (var middle (floor (/ (+ start end) 2)))
+        `(,@(range start middle) ,@(range middle end)))))
+
+(export map filter reduce range)
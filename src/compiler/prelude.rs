@@ -0,0 +1,35 @@
+use std::{path::PathBuf, sync::OnceLock};
+
+use crate::{optimizer, value::Value, vm::VM};
+
+use super::Compiler;
+
+const SOURCE: &str = include_str!("prelude.lisp");
+
+// The prelude's exports, compiled and run once per process.
+static EXPORTS: OnceLock<Vec<(String, Value)>> = OnceLock::new();
+
+/// The prelude's exports with their names, in the order they are exported.
+pub(super) fn exports() -> &'static [(String, Value)] {
+    EXPORTS.get_or_init(load)
+}
+
+/// The value the prelude exports as `name`.
+pub(super) fn lookup(name: &str) -> Option<&'static Value> {
+    exports()
+        .iter()
+        .find(|(export, _)| export == name)
+        .map(|(_, value)| value)
+}
+
+fn load() -> Vec<(String, Value)> {
+    let mut compiler = Compiler::new(false);
+    compiler.set_prelude(false);
+
+    let mut module = compiler.module(SOURCE.to_string(), "prelude", PathBuf::new());
+    optimizer::optimize(&mut module.result).unwrap_or_else(|err| panic!("Prelude failed: {}", err));
+
+    module
+        .evaluate("prelude", &mut VM::new())
+        .unwrap_or_else(|err| panic!("Prelude failed: {}", err))
+}
@@ -0,0 +1,432 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    compiler::{CompileResult, LineInfo, LocalVar},
+    disassembler::{local_name, op_code_name, slot_names},
+    value::Value,
+    vm::{
+        decode_address, is_jump, operand_count, CallFrame, OP_GET_VAR, OP_INC_VAR, OP_SET_VAR, VM,
+    },
+};
+
+const HELP: &str = "Commands:
+    step, s                 run one instruction, entering calls
+    next, n                 run one instruction, stepping over calls
+    finish, f               run until the current function returns
+    continue, c             run until a breakpoint or the end of the program
+    break <line>            pause before the first instruction of a source line
+    break [function]@<addr> pause at a bytecode address (hex), default in the current function
+    delete <n>              remove breakpoint <n>
+    breakpoints             list breakpoints
+    where, w                show the current instruction
+    backtrace, bt           show the call frames
+    locals, l               show the variables of the current frame
+    stack                   show the whole value stack
+    registers, r            show ip, sp and bp
+    quit, q                 stop debugging";
+
+enum Breakpoint {
+    Line(usize),
+    Address {
+        // The key of the function in `Debugger::functions`.
+        function: *const u8,
+        name: String,
+        address: usize,
+    },
+}
+
+struct FunctionInfo {
+    name: String,
+    locals: Vec<LocalVar>,
+    lines: Vec<LineInfo>,
+}
+
+/// Result of letting the program run for a while.
+enum Stop {
+    Paused,
+    Finished,
+}
+
+/// Drives a `VM` one instruction at a time from commands read from `input`.
+pub struct Debugger<R: BufRead, W: Write> {
+    // Keyed by where the function's bytecode lives, which the frames running
+    // it share, so functions with the same name stay apart.
+    functions: HashMap<*const u8, FunctionInfo>,
+    breakpoints: Vec<Breakpoint>,
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Debugger<R, W> {
+    pub fn new(program: &CompileResult, input: R, output: W) -> Debugger<R, W> {
+        let mut functions = HashMap::new();
+        // Moving the program into the VM leaves its bytecode where it is.
+        functions.insert(
+            program.bytecode.as_ptr(),
+            FunctionInfo {
+                name: String::from("main"),
+                locals: program.locals.clone(),
+                lines: program.lines.clone(),
+            },
+        );
+        collect_functions(&program.constants, &mut functions);
+
+        Debugger {
+            functions,
+            breakpoints: vec![],
+            input,
+            output,
+        }
+    }
+
+    /// Starts `program` paused before its first instruction and handles
+    /// commands until it finishes or the user quits.
+    pub fn run(&mut self, vm: &mut VM, program: CompileResult) -> io::Result<()> {
+        vm.start(program.constants, program.bytecode);
+        self.print_location(vm)?;
+
+        loop {
+            write!(self.output, "(vm) ")?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else {
+                continue;
+            };
+            let argument = words.next();
+
+            let stop = match command {
+                "step" | "s" => self.step(vm)?,
+                "next" | "n" => {
+                    let depth = vm.frames().len();
+                    self.run_while(vm, |vm| vm.frames().len() > depth)?
+                }
+                "finish" | "f" => {
+                    let depth = vm.frames().len();
+                    self.run_while(vm, |vm| vm.frames().len() >= depth)?
+                }
+                "continue" | "c" => self.run_while(vm, |_| true)?,
+                "break" | "b" => {
+                    self.add_breakpoint(vm, argument)?;
+                    continue;
+                }
+                "delete" | "d" => {
+                    self.delete_breakpoint(argument)?;
+                    continue;
+                }
+                "breakpoints" => {
+                    self.print_breakpoints()?;
+                    continue;
+                }
+                "where" | "w" => Stop::Paused,
+                "backtrace" | "bt" => {
+                    self.print_backtrace(vm)?;
+                    continue;
+                }
+                "locals" | "l" => {
+                    self.print_locals(vm)?;
+                    continue;
+                }
+                "stack" => {
+                    self.print_stack(vm)?;
+                    continue;
+                }
+                "registers" | "r" => {
+                    self.print_registers(vm)?;
+                    continue;
+                }
+                "help" | "h" => {
+                    writeln!(self.output, "{}", HELP)?;
+                    continue;
+                }
+                "quit" | "q" => return Ok(()),
+                _ => {
+                    writeln!(self.output, "Unknown command {}, try help", command)?;
+                    continue;
+                }
+            };
+
+            match stop {
+                Stop::Paused => self.print_location(vm)?,
+                Stop::Finished => return Ok(()),
+            }
+        }
+    }
+
+    fn step(&mut self, vm: &mut VM) -> io::Result<Stop> {
+        match vm.step() {
+            Ok(Some(result)) => {
+                writeln!(self.output, "Program finished: {}", result)?;
+                Ok(Stop::Finished)
+            }
+            Ok(None) => Ok(Stop::Paused),
+            Err(err) => {
+                writeln!(self.output, "Runtime error: {}", err)?;
+                Ok(Stop::Finished)
+            }
+        }
+    }
+
+    /// Steps at least once, then keeps going while `condition` holds and no
+    /// breakpoint is reached.
+    fn run_while(&mut self, vm: &mut VM, condition: impl Fn(&VM) -> bool) -> io::Result<Stop> {
+        loop {
+            if let Stop::Finished = self.step(vm)? {
+                return Ok(Stop::Finished);
+            }
+
+            if self.is_at_breakpoint(vm) {
+                writeln!(self.output, "Breakpoint reached")?;
+                return Ok(Stop::Paused);
+            }
+
+            if !condition(vm) {
+                return Ok(Stop::Paused);
+            }
+        }
+    }
+
+    fn is_at_breakpoint(&self, vm: &VM) -> bool {
+        let Some(frame) = vm.frames().last() else {
+            return false;
+        };
+
+        self.breakpoints.iter().any(|breakpoint| match breakpoint {
+            Breakpoint::Line(line) => self.function(frame).is_some_and(|function| {
+                function
+                    .lines
+                    .iter()
+                    .any(|info| info.line == *line && info.address == frame.ip)
+            }),
+            Breakpoint::Address {
+                function, address, ..
+            } => *function == frame.bytecode().as_ptr() && *address == frame.ip,
+        })
+    }
+
+    fn add_breakpoint(&mut self, vm: &VM, argument: Option<&str>) -> io::Result<()> {
+        let breakpoint = match argument {
+            Some(argument) if argument.contains('@') => {
+                let (name, address) = argument.split_once('@').unwrap();
+                let function = if name.is_empty() {
+                    vm.frames()
+                        .last()
+                        .map(|frame| (frame.bytecode().as_ptr(), frame.name().to_string()))
+                } else {
+                    let mut matches = self
+                        .functions
+                        .iter()
+                        .filter(|(_, info)| info.name == name)
+                        .map(|(&key, info)| (key, info.name.clone()));
+                    let function = matches.next();
+                    if matches.next().is_some() {
+                        return writeln!(
+                            self.output,
+                            "Several functions are called {}, break on a line instead",
+                            name
+                        );
+                    }
+                    function
+                };
+
+                match (
+                    function,
+                    usize::from_str_radix(address.trim_start_matches("0x"), 16),
+                ) {
+                    (Some((function, name)), Ok(address)) => Some(Breakpoint::Address {
+                        function,
+                        name,
+                        address,
+                    }),
+                    _ => None,
+                }
+            }
+            Some(argument) => argument.parse().ok().map(Breakpoint::Line),
+            None => None,
+        };
+
+        match breakpoint {
+            Some(breakpoint) => {
+                self.breakpoints.push(breakpoint);
+                writeln!(
+                    self.output,
+                    "Breakpoint {}: {}",
+                    self.breakpoints.len(),
+                    describe_breakpoint(self.breakpoints.last().unwrap())
+                )
+            }
+            None => writeln!(
+                self.output,
+                "Usage: break <line> | break [function]@<address>"
+            ),
+        }
+    }
+
+    fn delete_breakpoint(&mut self, argument: Option<&str>) -> io::Result<()> {
+        match argument.and_then(|argument| argument.parse::<usize>().ok()) {
+            Some(number) if number >= 1 && number <= self.breakpoints.len() => {
+                self.breakpoints.remove(number - 1);
+                writeln!(self.output, "Deleted breakpoint {}", number)
+            }
+            _ => writeln!(self.output, "No such breakpoint"),
+        }
+    }
+
+    fn print_breakpoints(&mut self) -> io::Result<()> {
+        if self.breakpoints.is_empty() {
+            return writeln!(self.output, "No breakpoints");
+        }
+
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            writeln!(
+                self.output,
+                "{:>3}  {}",
+                index + 1,
+                describe_breakpoint(breakpoint)
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn print_location(&mut self, vm: &VM) -> io::Result<()> {
+        let Some(frame) = vm.frames().last() else {
+            return Ok(());
+        };
+
+        let line = self.line(frame);
+        let instruction = self.describe_instruction(frame);
+
+        writeln!(
+            self.output,
+            "{}@{:04x}{}  {}",
+            frame.name(),
+            frame.ip,
+            line,
+            instruction
+        )
+    }
+
+    fn print_backtrace(&mut self, vm: &VM) -> io::Result<()> {
+        for (depth, frame) in vm.frames().iter().enumerate().rev() {
+            let line = self.line(frame);
+            writeln!(
+                self.output,
+                "#{:<3} {}@{:04x}{}  bp={}",
+                depth,
+                frame.name(),
+                frame.ip,
+                line,
+                frame.bp
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn print_locals(&mut self, vm: &VM) -> io::Result<()> {
+        let Some(frame) = vm.frames().last() else {
+            return Ok(());
+        };
+
+        let names = self
+            .function(frame)
+            .map(|function| slot_names(&function.locals, frame.ip))
+            .unwrap_or_default();
+
+        for (slot, value) in vm.stack()[frame.bp..].iter().enumerate() {
+            let name = names.get(slot).map_or("", String::as_str);
+            writeln!(self.output, "{:>3}  {:<12} {}", slot, name, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn print_stack(&mut self, vm: &VM) -> io::Result<()> {
+        for (index, value) in vm.stack().iter().enumerate() {
+            let marker = if index == vm.bp() { "bp ->" } else { "" };
+            writeln!(self.output, "{:>5} {:>4}  {}", marker, index, value)?;
+        }
+        writeln!(self.output, "sp -> {:>4}", vm.sp())
+    }
+
+    fn print_registers(&mut self, vm: &VM) -> io::Result<()> {
+        let ip = vm.frames().last().map_or(0, |frame| frame.ip);
+        writeln!(self.output, "ip={:04x} sp={} bp={}", ip, vm.sp(), vm.bp())
+    }
+
+    fn function(&self, frame: &CallFrame) -> Option<&FunctionInfo> {
+        self.functions.get(&frame.bytecode().as_ptr())
+    }
+
+    fn line(&self, frame: &CallFrame) -> String {
+        self.function(frame)
+            .and_then(|function| {
+                function
+                    .lines
+                    .iter()
+                    .take_while(|info| info.address <= frame.ip)
+                    .last()
+            })
+            .map_or(String::new(), |info| format!(" line {}", info.line))
+    }
+
+    fn describe_instruction(&self, frame: &CallFrame) -> String {
+        let (bytecode, ip) = (frame.bytecode(), frame.ip);
+        let Some(&op_code) = bytecode.get(ip) else {
+            return String::from("<end>");
+        };
+        let operands = &bytecode[ip + 1..ip + 1 + operand_count(op_code).unwrap_or(0)];
+
+        let mut description = op_code_name(op_code);
+        if is_jump(op_code) {
+            description.push_str(&format!(" {:04x}", decode_address(operands)));
+        } else {
+            for operand in operands {
+                description.push_str(&format!(" {}", operand));
+            }
+        }
+
+        if let OP_GET_VAR | OP_SET_VAR | OP_INC_VAR = op_code {
+            if let Some(name) = self
+                .function(frame)
+                .and_then(|function| local_name(&function.locals, operands[0], ip))
+            {
+                description.push_str(&format!(" ({})", name));
+            }
+        }
+
+        description
+    }
+}
+
+fn collect_functions(constants: &[Value], functions: &mut HashMap<*const u8, FunctionInfo>) {
+    for constant in constants {
+        if let Value::Function { val: function } = constant {
+            functions.insert(
+                function.bytecode.as_ptr(),
+                FunctionInfo {
+                    name: function.name.clone(),
+                    locals: function.locals.clone(),
+                    lines: function.lines.clone(),
+                },
+            );
+            collect_functions(&function.constants, functions);
+        }
+    }
+}
+
+fn describe_breakpoint(breakpoint: &Breakpoint) -> String {
+    match breakpoint {
+        Breakpoint::Line(line) => format!("line {}", line),
+        Breakpoint::Address { name, address, .. } => format!("{}@{:04x}", name, address),
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::{self, Write};
+
+use crate::{compiler::LocalVar, value::Value, vm::*};
+
+use super::{disassemble, labels, op_code_name, operand_text, Instruction};
+
+/// A run of instructions that is only entered at the top and only left at
+/// the bottom. `start..end` indexes the instruction list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    /// Indices of the blocks execution may continue in. Jumps to the end of
+    /// the bytecode leave the function and have no successor.
+    pub successors: Vec<usize>,
+}
+
+pub fn control_flow_graph(instructions: &[Instruction]) -> Vec<BasicBlock> {
+    let index_of = |address: usize| {
+        instructions
+            .iter()
+            .position(|instruction| instruction.address == address)
+    };
+
+    // A block starts at the entry, at every jump target and after every
+    // instruction that does not simply fall through.
+    let mut is_leader = vec![false; instructions.len()];
+    if !is_leader.is_empty() {
+        is_leader[0] = true;
+    }
+    for (index, instruction) in instructions.iter().enumerate() {
+        if let Some(target) = instruction.jump_target().and_then(index_of) {
+            is_leader[target] = true;
+        }
+        if ends_block(instruction) && index + 1 < instructions.len() {
+            is_leader[index + 1] = true;
+        }
+    }
+
+    let starts: Vec<usize> = (0..instructions.len())
+        .filter(|&index| is_leader[index])
+        .collect();
+    let block_of = |index: usize| starts.iter().position(|&start| start == index);
+
+    let mut blocks = vec![];
+    for (block, &start) in starts.iter().enumerate() {
+        let end = starts.get(block + 1).copied().unwrap_or(instructions.len());
+        let last = &instructions[end - 1];
+
+        let mut successors = vec![];
+        let falls_through = !matches!(last.op_code, OP_JUMP | OP_THROW | OP_HALT | OP_RETURN);
+        if falls_through && end < instructions.len() {
+            successors.push(block + 1);
+        }
+        if let Some(target) = last.jump_target().and_then(index_of).and_then(block_of) {
+            if !successors.contains(&target) {
+                successors.push(target);
+            }
+        }
+
+        blocks.push(BasicBlock {
+            start,
+            end,
+            successors,
+        });
+    }
+
+    blocks
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    !instruction.is_byte()
+        && matches!(
+            instruction.op_code,
+            OP_JUMP | OP_JUMP_IF_FALSE | OP_TRY | OP_THROW | OP_HALT | OP_RETURN
+        )
+}
+
+/// Writes the control-flow graph of `main` and every function reachable
+/// through the constant pool as one Graphviz digraph, with a cluster per
+/// function.
+pub fn write_dot<W: Write>(
+    writer: &mut W,
+    bytecode: &[u8],
+    constants: &[Value],
+    locals: &[LocalVar],
+) -> io::Result<()> {
+    writeln!(writer, "digraph program {{")?;
+    writeln!(writer, "    node [shape=box, fontname=\"monospace\"];")?;
+
+    let mut function_count = 0;
+    write_function_dot(
+        writer,
+        &mut function_count,
+        "main",
+        bytecode,
+        constants,
+        locals,
+    )?;
+
+    writeln!(writer, "}}")
+}
+
+fn write_function_dot<W: Write>(
+    writer: &mut W,
+    function_count: &mut usize,
+    name: &str,
+    bytecode: &[u8],
+    constants: &[Value],
+    locals: &[LocalVar],
+) -> io::Result<()> {
+    let id = *function_count;
+    *function_count += 1;
+
+    let instructions = disassemble(bytecode, constants, locals);
+    let labels = labels(&instructions);
+    let blocks = control_flow_graph(&instructions);
+
+    writeln!(writer, "    subgraph cluster_{} {{", id)?;
+    writeln!(writer, "        label=\"{}\";", escape(name))?;
+
+    for (index, block) in blocks.iter().enumerate() {
+        let mut text = String::new();
+        for instruction in &instructions[block.start..block.end] {
+            if let Some(label) = labels.get(&instruction.address) {
+                text.push_str(&format!("{}:\\l", label));
+            }
+            let line = format!(
+                "{:04x}  {} {}",
+                instruction.address,
+                if instruction.is_byte() {
+                    String::from(".byte")
+                } else {
+                    op_code_name(instruction.op_code)
+                },
+                operand_text(instruction)
+            );
+            text.push_str(&escape(line.trim_end()));
+            text.push_str("\\l");
+        }
+        writeln!(writer, "        f{}_b{} [label=\"{}\"];", id, index, text)?;
+    }
+
+    for (index, block) in blocks.iter().enumerate() {
+        let last = &instructions[block.end - 1];
+        for &successor in &block.successors {
+            let is_jump = last
+                .jump_target()
+                .is_some_and(|target| instructions[blocks[successor].start].address == target);
+
+            let mut attributes = vec![];
+            if last.op_code == OP_JUMP_IF_FALSE {
+                attributes.push(if is_jump {
+                    "label=\"false\""
+                } else {
+                    "label=\"true\""
+                });
+            }
+            if last.op_code == OP_TRY && is_jump {
+                attributes.push("label=\"catch\"");
+            }
+            if is_jump && last.is_back_edge() {
+                if attributes.is_empty() {
+                    attributes.push("label=\"loop\"");
+                }
+                attributes.push("style=dashed");
+            }
+
+            write!(
+                writer,
+                "        f{}_b{} -> f{}_b{}",
+                id, index, id, successor
+            )?;
+            if !attributes.is_empty() {
+                write!(writer, " [{}]", attributes.join(", "))?;
+            }
+            writeln!(writer, ";")?;
+        }
+    }
+
+    writeln!(writer, "    }}")?;
+
+    for constant in constants {
+        if let Value::Function { val: function } = constant {
+            write_function_dot(
+                writer,
+                function_count,
+                &function.name,
+                &function.bytecode,
+                &function.constants,
+                &function.locals,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -1,146 +1,331 @@
-use crate::{compiler::Var, value::Value, vm::*};
-
-pub fn disassemble(bytecode: &Vec<u8>, constants: &Vec<Value>, vars: &Vec<Var>) {
-    inner_disassmeble(bytecode, constants, vars, "main");
-
-    for constant in constants {
-        if let Value::Function {
-            name,
-            bytecode,
-            constants,
-            disassembler_vars,
-            ..
-        } = constant
-        {
-            inner_disassmeble(bytecode, constants, disassembler_vars, name);
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use crate::{
+    compiler::{CompileResult, LineInfo, LocalVar},
+    value::Value,
+    vm::*,
+};
+
+pub use self::graph::{control_flow_graph, write_dot, BasicBlock};
+
+mod graph;
+
+/// One decoded instruction. Bytes that do not start a known instruction, or
+/// an instruction cut off by the end of the bytecode, become a single
+/// `.byte` entry with no operands.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: usize,
+    pub op_code: u8,
+    pub operands: Vec<u8>,
+    /// What each operand refers to, such as a constant's value or a
+    /// variable's name, when that is known.
+    pub annotations: Vec<Option<String>>,
+}
+
+impl Instruction {
+    pub fn is_byte(&self) -> bool {
+        operand_count(self.op_code) != Some(self.operands.len())
+    }
+
+    /// Number of bytes the instruction occupies.
+    pub fn size(&self) -> usize {
+        1 + self.operands.len()
+    }
+
+    pub fn jump_target(&self) -> Option<usize> {
+        if is_jump(self.op_code) && !self.is_byte() {
+            Some(decode_address(&self.operands))
+        } else {
+            None
         }
     }
+
+    /// A jump to itself or an earlier instruction closes a loop.
+    pub fn is_back_edge(&self) -> bool {
+        self.jump_target()
+            .is_some_and(|target| target <= self.address)
+    }
 }
 
-fn inner_disassmeble(bytecode: &Vec<u8>, constants: &Vec<Value>, vars: &Vec<Var>, name: &str) {
-    println!("\n--------------Disassembler ({})----------------\n", name);
+/// Names `L1`, `L2`, ... for every jump target, numbered by address.
+pub fn labels(instructions: &[Instruction]) -> BTreeMap<usize, String> {
+    let mut labels: BTreeMap<usize, String> = instructions
+        .iter()
+        .filter_map(Instruction::jump_target)
+        .map(|target| (target, String::new()))
+        .collect();
+
+    for (index, label) in labels.values_mut().enumerate() {
+        *label = format!("L{}", index + 1);
+    }
+
+    labels
+}
 
-    let mut var_pointer = 0;
+/// Decodes the bytecode of one function. Nested functions are left in
+/// `constants`; `write_program` lists them too.
+pub fn disassemble(bytecode: &[u8], constants: &[Value], locals: &[LocalVar]) -> Vec<Instruction> {
+    let mut instructions = vec![];
 
     let mut ip = 0;
     while ip < bytecode.len() {
-        let instruction = bytecode[ip];
-        let address: u16 = ip.try_into().unwrap();
-
-        match instruction {
-            OP_ADD | OP_SUB | OP_MUL | OP_DIV | OP_GT | OP_GTE | OP_LT | OP_LTE | OP_EQ
-            | OP_POP | OP_CALL | OP_RETURN => disassemble_binary_instruction(address, instruction),
-            OP_CONST => {
-                let position = bytecode[ip + 1];
-                ip += 1;
-                let value = match &constants[position as usize] {
-                    Value::Number { val: num } => num.to_string(),
-                    Value::String { val: str } => str.clone(),
-                    Value::Boolean { val } => val.to_string(),
-                    Value::Function { name, .. } => name.to_string(),
-                };
-
-                dump_bytes(
-                    address,
-                    vec![OP_CONST, position],
-                    instruction,
-                    format!("{} ({})", position, value),
-                );
-            }
-            OP_HALT => dump_bytes(address, vec![OP_HALT], instruction, String::from("")),
-            OP_JUMP => {
-                let position = bytecode[ip + 1];
-                ip += 1;
-                dump_bytes(
-                    address,
-                    vec![OP_JUMP, position],
-                    instruction,
-                    format!("{:04x}", position),
-                );
-            }
-            OP_JUMP_IF_FALSE => {
-                let position = bytecode[ip + 1];
-                ip += 1;
-                dump_bytes(
-                    address,
-                    vec![OP_JUMP_IF_FALSE, position],
-                    instruction,
-                    format!("{:04x}", position),
-                );
-            }
-            OP_GET_VAR => {
-                let position = bytecode[ip + 1];
-                ip += 1;
-                dump_bytes(
-                    address,
-                    vec![OP_GET_VAR, position],
-                    instruction,
-                    format!("{} ({})", position, vars[var_pointer].name),
-                );
-
-                var_pointer += 1;
-            }
-            OP_SET_VAR => {
-                let position = bytecode[ip + 1];
-                ip += 1;
-                dump_bytes(
-                    address,
-                    vec![OP_SET_VAR, position],
-                    instruction,
-                    format!("{} ({})", position, vars[var_pointer].name),
-                );
-
-                var_pointer += 1;
-            }
-            OP_SCOPE_EXIT => {
-                let number_of_variables = bytecode[ip + 1];
-                ip += 1;
-                dump_bytes(
-                    address,
-                    vec![OP_SCOPE_EXIT, number_of_variables],
-                    instruction,
-                    format!("{}", number_of_variables),
-                );
-            }
-            OP_PARAM => {
-                let position = bytecode[ip + 1];
+        let op_code = bytecode[ip];
+
+        let operands = match operand_count(op_code) {
+            Some(count) if ip + count < bytecode.len() => bytecode[ip + 1..ip + 1 + count].to_vec(),
+            _ => {
+                instructions.push(Instruction {
+                    address: ip,
+                    op_code,
+                    operands: vec![],
+                    annotations: vec![],
+                });
                 ip += 1;
-                dump_bytes(
-                    address,
-                    vec![OP_PARAM, position],
-                    instruction,
-                    format!("{}", position),
-                );
+                continue;
             }
-            _ => {
-                panic!("Invalid instruction");
+        };
+
+        let constant = |index: u8| constants.get(index as usize).map(constant_name);
+        let local = |slot: u8| local_name(locals, slot, ip).map(String::from);
+        let annotations = match op_code {
+            OP_CONST => vec![constant(operands[0])],
+            OP_GET_VAR | OP_SET_VAR => vec![local(operands[0])],
+            OP_INC_VAR => vec![local(operands[0]), constant(operands[1])],
+            OP_NATIVE => vec![NATIVES
+                .get(operands[0] as usize)
+                .map(|native| native.name.to_string())],
+            _ => vec![None; operands.len()],
+        };
+
+        ip += 1 + operands.len();
+        instructions.push(Instruction {
+            address: ip - 1 - operands.len(),
+            op_code,
+            operands,
+            annotations,
+        });
+    }
+
+    let labels = labels(&instructions);
+    for instruction in instructions.iter_mut() {
+        if let Some(target) = instruction.jump_target() {
+            instruction.annotations = vec![labels.get(&target).cloned()];
+        }
+    }
+
+    instructions
+}
+
+/// Writes the listing of a whole program in the syntax that
+/// `assembler::assemble` reads back: the constant pool, with functions as
+/// nested `.function` blocks, the ranges of local variables, then the
+/// instructions with their labels and `.line` markers.
+pub fn write_program<W: Write>(writer: &mut W, program: &CompileResult) -> io::Result<()> {
+    writeln!(writer, "; main")?;
+    write_chunk(
+        writer,
+        0,
+        &program.bytecode,
+        &program.constants,
+        &program.locals,
+        &program.lines,
+    )
+}
+
+fn write_chunk<W: Write>(
+    writer: &mut W,
+    depth: usize,
+    bytecode: &[u8],
+    constants: &[Value],
+    locals: &[LocalVar],
+    lines: &[LineInfo],
+) -> io::Result<()> {
+    let indent = "    ".repeat(depth);
+
+    for (index, constant) in constants.iter().enumerate() {
+        match constant {
+            Value::Function { val: function } => {
+                writeln!(
+                    writer,
+                    "{}.const {} .function {} {} {}",
+                    indent, index, function.name, function.arity, function.scope_level
+                )?;
+                write_chunk(
+                    writer,
+                    depth + 1,
+                    &function.bytecode,
+                    &function.constants,
+                    &function.locals,
+                    &function.lines,
+                )?;
+                writeln!(writer, "{}.end", indent)?;
             }
+            _ => writeln!(
+                writer,
+                "{}.const {} {}",
+                indent,
+                index,
+                constant_name(constant)
+            )?,
         }
-        ip += 1;
     }
+
+    for local in locals {
+        writeln!(
+            writer,
+            "{}.local {} {} {:04x} {:04x}",
+            indent, local.slot, local.name, local.start, local.end
+        )?;
+    }
+
+    if !constants.is_empty() || !locals.is_empty() {
+        writeln!(writer)?;
+    }
+
+    write_body(
+        writer,
+        &indent,
+        &disassemble(bytecode, constants, locals),
+        lines,
+    )
 }
 
-fn disassemble_binary_instruction(address: u16, instruction: u8) {
-    dump_bytes(address, vec![instruction], instruction, String::from(""))
+/// Writes one line per instruction, preceded by a `L1:` line wherever a
+/// jump lands.
+pub fn write_instructions<W: Write>(
+    writer: &mut W,
+    instructions: &[Instruction],
+) -> io::Result<()> {
+    write_body(writer, "", instructions, &[])
 }
 
-fn dump_bytes(address: u16, bytes: Vec<u8>, instruction: u8, info: String) {
-    let bytes_string = bytes
-        .iter()
+fn write_body<W: Write>(
+    writer: &mut W,
+    indent: &str,
+    instructions: &[Instruction],
+    lines: &[LineInfo],
+) -> io::Result<()> {
+    let labels = labels(instructions);
+
+    for instruction in instructions {
+        if let Some(label) = labels.get(&instruction.address) {
+            writeln!(writer, "{}{}:", indent, label)?;
+        }
+        if let Some(line) = lines
+            .iter()
+            .find(|line| line.address == instruction.address)
+        {
+            writeln!(writer, "{}.line {}", indent, line.line)?;
+        }
+        writeln!(writer, "{}{}", indent, format_instruction(instruction))?;
+    }
+
+    // Jumping to the end of the bytecode leaves the function.
+    let end = instructions
+        .last()
+        .map_or(0, |last| last.address + last.size());
+    if let Some(label) = labels.get(&end) {
+        writeln!(writer, "{}{}:", indent, label)?;
+    }
+
+    Ok(())
+}
+
+pub fn format_instruction(instruction: &Instruction) -> String {
+    let bytes = std::iter::once(&instruction.op_code)
+        .chain(&instruction.operands)
         .map(|b| format!("{:02x}", b))
         .collect::<Vec<String>>()
         .join(" ");
 
-    println!(
+    if instruction.is_byte() {
+        return format!(
+            "{:04x}      {:<14}{:<20}{:02x}",
+            instruction.address, bytes, ".byte", instruction.op_code
+        );
+    }
+
+    format!(
         "{:04x}      {:<14}{:<20}{}",
-        address,
-        bytes_string,
-        op_code_name(instruction),
-        info
-    );
+        instruction.address,
+        bytes,
+        op_code_name(instruction.op_code),
+        operand_text(instruction)
+    )
+    .trim_end()
+    .to_string()
+}
+
+fn operand_text(instruction: &Instruction) -> String {
+    // A jump's two operand bytes hold one address, shown as its label.
+    let mut operands = match instruction.jump_target() {
+        Some(target) => match instruction.annotations.first() {
+            Some(Some(label)) => label.clone(),
+            _ => format!("{:04x}", target),
+        },
+        None => instruction
+            .operands
+            .iter()
+            .zip(&instruction.annotations)
+            .map(|(operand, annotation)| match annotation {
+                Some(annotation) => format!("{} ({})", operand, annotation),
+                None => operand.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(" "),
+    };
+
+    if instruction.is_back_edge() {
+        operands.push_str("  ; loop");
+    }
+
+    operands
+}
+
+/// Name of the variable held in `slot` while the instruction at `ip` runs.
+pub fn local_name(locals: &[LocalVar], slot: u8, ip: usize) -> Option<&str> {
+    locals
+        .iter()
+        .rev()
+        .find(|local| local.slot == slot && local.start <= ip && ip < local.end)
+        .map(|local| local.name.as_str())
+}
+
+/// Names of the variables in slots `0..` of a frame that is about to run
+/// the instruction at `ip`; slots without a known name are `?`.
+pub fn slot_names(locals: &[LocalVar], ip: usize) -> Vec<String> {
+    let mut names = vec![];
+
+    for local in locals {
+        if local.start <= ip && ip < local.end {
+            let slot = local.slot as usize;
+            if names.len() <= slot {
+                names.resize(slot + 1, String::from("?"));
+            }
+            names[slot] = local.name.clone();
+        }
+    }
+
+    names
 }
 
-fn op_code_name(op_code: u8) -> String {
+fn constant_name(constant: &Value) -> String {
+    match constant {
+        Value::Number { val: num } => num.to_string(),
+        // Escaped, so that the listing stays one instruction per line and
+        // reads back with the assembler.
+        Value::String { val: str } => format!("{:?}", str),
+        Value::Boolean { val } => val.to_string(),
+        Value::Symbol { .. } | Value::List { .. } => format!("'{}", constant),
+        Value::Function { val } => val.name.to_string(),
+    }
+}
+
+pub fn op_code_name(op_code: u8) -> String {
     String::from(match op_code {
         OP_ADD => "ADD",
         OP_SUB => "SUB",
@@ -161,9 +346,18 @@ fn op_code_name(op_code: u8) -> String {
         OP_SCOPE_EXIT => "SCOPE_EXIT",
         OP_CALL => "CALL",
         OP_RETURN => "RETURN",
-        OP_PARAM => "PARAM",
-        _ => {
-            panic!("Invalid instruction");
-        }
+        OP_INC_VAR => "INC_VAR",
+        OP_LIST => "LIST",
+        OP_CONCAT => "CONCAT",
+        OP_GENSYM => "GENSYM",
+        OP_LENGTH => "LENGTH",
+        OP_NTH => "NTH",
+        OP_TRY => "TRY",
+        OP_END_TRY => "END_TRY",
+        OP_THROW => "THROW",
+        OP_TYPE_OF => "TYPE_OF",
+        OP_DROP => "DROP",
+        OP_NATIVE => "NATIVE",
+        _ => ".byte",
     })
 }
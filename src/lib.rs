@@ -0,0 +1,9 @@
+pub mod assembler;
+pub mod compiler;
+pub mod debugger;
+pub mod disassembler;
+pub mod optimizer;
+pub mod parser;
+pub mod value;
+pub mod verifier;
+pub mod vm;
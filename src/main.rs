@@ -1,53 +1,395 @@
-use self::value::Value;
-use parser::Parser;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
 
-use crate::compiler::Compiler;
+use vm::{
+    assembler,
+    compiler::{CompileResult, Compiler},
+    debugger::Debugger,
+    disassembler, optimizer,
+    parser::Parser,
+    verifier,
+    vm::{Profiler, Trace, VM},
+};
 
-mod compiler;
-mod disassembler;
-mod parser;
-mod value;
-mod vm;
+const BYTECODE_EXTENSION: &str = "vmc";
+const ASSEMBLY_EXTENSION: &str = "vasm";
+
+const USAGE: &str = "Usage:
+    vm                                  run the built-in example
+    vm [options] <file>                 run a source, .vasm assembly or precompiled .vmc file
+    vm compile <file> [-o <output>]     compile a source or .vasm file to a .vmc file
+    vm disassemble <file> [-o <output>] write the program as .vasm assembly
+    vm debug <file>                     step through a program interactively
+    vm macroexpand <file>               print the source with all macros expanded
+
+Options:
+    --disassemble                       print the bytecode before running
+    --no-prelude                        don't make map, filter, range and the rest available
+    --wrapped                           read a program written as one list of all its forms
+    --cfg <output>                      write the control-flow graph as Graphviz DOT
+    --fuel <n>                          stop after executing <n> instructions
+    --timeout <ms>                      stop after <ms> milliseconds
+    --max-memory <bytes>                roughly limit the bytes held by strings and lists
+    --max-stack <n>                     limit the stack to <n> values
+    --seed <n>                          seed random with <n> to make runs reproducible
+    --memory-report                     print peak memory use after running
+    --trace                             print every instruction and the stack to stderr
+    --trace-function <name>             only trace instructions of <name>, implies --trace
+    --profile                           print instruction and function statistics to stderr
+    --profile-folded <output>           write folded call stacks for flamegraph tools";
+
+#[derive(Default)]
+struct Limits {
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    max_heap_bytes: Option<usize>,
+    max_stack_size: Option<usize>,
+    seed: Option<u64>,
+    is_memory_report: bool,
+    is_trace: bool,
+    trace_functions: Vec<String>,
+    is_profile: bool,
+    profile_folded: Option<String>,
+}
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        None => run_example(),
+        Some("compile") => compile_command(&args[1..]),
+        Some("disassemble") => disassemble_command(&args[1..]),
+        Some("debug") => debug_command(&args[1..]),
+        Some("macroexpand") => macroexpand_command(&args[1..]),
+        Some("--help" | "-h") => println!("{}", USAGE),
+        Some(_) => run_command(&args),
+    }
+}
+
+fn run_example() {
     let is_debug = true;
     let source_code = String::from(
         "
-        (
-            (def fac (x) (begin
-                (if (= x 10) 100 200)
-            ))
-            (call fac 10)
-        )
+        (def fac (x) (begin
+            (if (= x 10) 100 200)
+        ))
+        (call fac 10)
         ",
     );
 
+    let limits = Limits::default();
+    let result = compile_source(source_code, None, is_debug, true, false, &limits);
+
+    disassemble(&result);
+    run(result, &limits);
+}
+
+fn run_command(args: &[String]) {
+    let mut is_debug = false;
+    let mut has_prelude = true;
+    let mut is_wrapped = false;
+    let mut cfg = None;
+    let mut limits = Limits::default();
+    let mut path = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--disassemble" => is_debug = true,
+            "--no-prelude" => has_prelude = false,
+            "--wrapped" => is_wrapped = true,
+            "--cfg" => {
+                let Some(output) = args.next() else {
+                    exit_with_usage();
+                };
+                cfg = Some(output);
+            }
+            "--fuel" => limits.fuel = Some(parse_number(args.next())),
+            "--timeout" => limits.timeout = Some(Duration::from_millis(parse_number(args.next()))),
+            "--max-memory" => limits.max_heap_bytes = Some(parse_number(args.next()) as usize),
+            "--max-stack" => limits.max_stack_size = Some(parse_number(args.next()) as usize),
+            "--seed" => limits.seed = Some(parse_number(args.next())),
+            "--memory-report" => limits.is_memory_report = true,
+            "--trace" => limits.is_trace = true,
+            "--trace-function" => {
+                let Some(name) = args.next() else {
+                    exit_with_usage();
+                };
+                limits.is_trace = true;
+                limits.trace_functions.push(name.clone());
+            }
+            "--profile" => limits.is_profile = true,
+            "--profile-folded" => {
+                let Some(output) = args.next() else {
+                    exit_with_usage();
+                };
+                limits.profile_folded = Some(output.clone());
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+
+    let Some(path) = path else {
+        exit_with_usage();
+    };
+
+    let result = load(
+        Path::new(path),
+        is_debug || cfg.is_some(),
+        has_prelude,
+        is_wrapped,
+        &limits,
+    );
+
+    if is_debug {
+        disassemble(&result);
+    }
+    if let Some(output) = cfg {
+        let written = fs::File::create(output).and_then(|mut file| {
+            disassembler::write_dot(
+                &mut file,
+                &result.bytecode,
+                &result.constants,
+                &result.locals,
+            )
+        });
+        if let Err(err) = written {
+            exit_with_error(&format!("{}: {}", output, err));
+        }
+    }
+    run(result, &limits);
+}
+
+fn parse_number(arg: Option<&String>) -> u64 {
+    arg.and_then(|arg| arg.parse().ok())
+        .unwrap_or_else(|| exit_with_usage())
+}
+
+fn compile_command(args: &[String]) {
+    let (input, output) = input_and_output(args, BYTECODE_EXTENSION);
+
+    let result = load(&input, true, true, false, &Limits::default());
+
+    let mut file = fs::File::create(&output)
+        .unwrap_or_else(|err| exit_with_error(&format!("{}: {}", output.display(), err)));
+    if let Err(err) = result.write_to(&mut file) {
+        exit_with_error(&format!("{}: {}", output.display(), err));
+    }
+}
+
+fn disassemble_command(args: &[String]) {
+    let (input, output) = input_and_output(args, ASSEMBLY_EXTENSION);
+
+    let result = load(&input, true, true, false, &Limits::default());
+
+    let mut file = fs::File::create(&output)
+        .unwrap_or_else(|err| exit_with_error(&format!("{}: {}", output.display(), err)));
+    if let Err(err) = disassembler::write_program(&mut file, &result) {
+        exit_with_error(&format!("{}: {}", output.display(), err));
+    }
+}
+
+/// Parses `<input> [-o <output>]`, defaulting the output to the input with
+/// `extension`.
+fn input_and_output(args: &[String], extension: &str) -> (PathBuf, PathBuf) {
+    let mut input = None;
+    let mut output = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = args.next(),
+            _ if input.is_none() => input = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+
+    let Some(input) = input else {
+        exit_with_usage();
+    };
+    let input = PathBuf::from(input);
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => input.with_extension(extension),
+    };
+
+    (input, output)
+}
+
+fn debug_command(args: &[String]) {
+    let [path] = args else {
+        exit_with_usage();
+    };
+
+    let program = load(Path::new(path), true, true, false, &Limits::default());
+    if let Err(err) = verifier::verify(&program) {
+        exit_with_error(&format!("invalid bytecode: {}", err));
+    }
+
+    let stdin = io::stdin();
+    let mut debugger = Debugger::new(&program, stdin.lock(), io::stdout());
+    if let Err(err) = debugger.run(&mut VM::new(), program) {
+        exit_with_error(&err.to_string());
+    }
+}
+
+fn macroexpand_command(args: &[String]) {
+    let [path] = args else {
+        exit_with_usage();
+    };
+
+    for datum in Parser::new(read_source(Path::new(path))).expand() {
+        println!("{}", datum);
+    }
+}
+
+/// Loads a precompiled `.vmc` file, assembles a `.vasm` file, or compiles
+/// any other file as source.
+fn load(
+    path: &Path,
+    is_debug: bool,
+    has_prelude: bool,
+    is_wrapped: bool,
+    limits: &Limits,
+) -> CompileResult {
+    let extension = path.extension().and_then(|ext| ext.to_str());
+
+    if extension == Some(BYTECODE_EXTENSION) {
+        let mut file = fs::File::open(path)
+            .unwrap_or_else(|err| exit_with_error(&format!("{}: {}", path.display(), err)));
+
+        CompileResult::read_from(&mut file)
+            .unwrap_or_else(|err| exit_with_error(&format!("{}: {}", path.display(), err)))
+    } else if extension == Some(ASSEMBLY_EXTENSION) {
+        assembler::assemble(&read_source(path))
+            .unwrap_or_else(|err| exit_with_error(&format!("{}: {}", path.display(), err)))
+    } else {
+        compile_source(
+            read_source(path),
+            Some(path),
+            is_debug,
+            has_prelude,
+            is_wrapped,
+            limits,
+        )
+    }
+}
+
+fn read_source(path: &Path) -> String {
+    fs::read_to_string(path)
+        .unwrap_or_else(|err| exit_with_error(&format!("{}: {}", path.display(), err)))
+}
+
+fn compile_source(
+    source_code: String,
+    path: Option<&Path>,
+    is_debug: bool,
+    has_prelude: bool,
+    is_wrapped: bool,
+    limits: &Limits,
+) -> CompileResult {
+    let is_optimize = true;
+    // Macros and imported modules run while compiling, under the same
+    // limits as the program.
+    let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+
     let mut code_parser = Parser::new(source_code);
+    code_parser.set_limits(limits.fuel, deadline);
+    code_parser.set_wrapped(is_wrapped);
     let res = code_parser.parse();
 
     let mut compiler = Compiler::new(is_debug);
+    compiler.set_prelude(has_prelude);
+    compiler.set_limits(limits.fuel, deadline);
+    if let Some(path) = path {
+        compiler.set_path(path);
+    }
     compiler.compile(res);
 
-    disassembler::disassemble(
-        &compiler.result.bytecode,
-        &compiler.result.constants,
-        &compiler.result.disassembler_vars,
-    );
-
-    let mut virtual_machine = vm::VM::new();
-    let result = virtual_machine.exec(compiler.result.constants, compiler.result.bytecode);
-    match &result {
-        Value::Number { val: num } => {
-            println!("\nResult: {}", num);
+    if is_optimize {
+        if let Err(err) = optimizer::optimize(&mut compiler.result) {
+            exit_with_error(&format!("invalid bytecode: {}", err));
         }
-        Value::String { val: str } => {
-            println!("\nResult: {}", str);
-        }
-        Value::Boolean { val } => {
-            println!("\nResult: {}", val);
+    }
+
+    compiler.result
+}
+
+fn disassemble(result: &CompileResult) {
+    let written = disassembler::write_program(&mut io::stdout(), result);
+    if let Err(err) = written {
+        exit_with_error(&err.to_string());
+    }
+}
+
+fn run(result: CompileResult, limits: &Limits) {
+    if let Err(err) = verifier::verify(&result) {
+        exit_with_error(&format!("invalid bytecode: {}", err));
+    }
+
+    let mut virtual_machine = VM::new();
+    virtual_machine.set_fuel(limits.fuel);
+    virtual_machine.set_deadline(limits.timeout.map(|timeout| Instant::now() + timeout));
+    virtual_machine.set_max_heap_bytes(limits.max_heap_bytes);
+    if let Some(max_stack_size) = limits.max_stack_size {
+        virtual_machine.set_max_stack_size(max_stack_size);
+    }
+    if let Some(seed) = limits.seed {
+        virtual_machine.set_seed(seed);
+    }
+    if limits.is_trace {
+        let trace = limits
+            .trace_functions
+            .iter()
+            .fold(Trace::new(Box::new(io::stderr())), |trace, name| {
+                trace.function(name)
+            });
+        virtual_machine.set_trace(Some(trace));
+    }
+    if limits.is_profile || limits.profile_folded.is_some() {
+        virtual_machine.set_profiler(Some(Profiler::new()));
+    }
+
+    let result = virtual_machine.exec(result.constants, result.bytecode);
+
+    if limits.is_memory_report {
+        let usage = virtual_machine.memory_usage();
+        eprintln!("Peak heap bytes:  {}", usage.peak_heap_bytes);
+        eprintln!("Peak stack size:  {}", usage.peak_stack_size);
+        eprintln!("Peak call depth:  {}", usage.peak_call_depth);
+    }
+
+    if let Some(profiler) = virtual_machine.profiler() {
+        if limits.is_profile {
+            if let Err(err) = profiler.write_report(&mut io::stderr()) {
+                exit_with_error(&err.to_string());
+            }
         }
-        Value::Function { name, .. } => {
-            println!("\nResult: (function) {}", name);
+
+        if let Some(output) = &limits.profile_folded {
+            let written =
+                fs::File::create(output).and_then(|mut file| profiler.write_folded(&mut file));
+            if let Err(err) = written {
+                exit_with_error(&format!("{}: {}", output, err));
+            }
         }
     }
+
+    let result = result.unwrap_or_else(|err| exit_with_error(&format!("runtime error: {}", err)));
+    println!("\nResult: {}", result);
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
 }
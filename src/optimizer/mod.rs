@@ -0,0 +1,301 @@
+use std::{fmt, sync::Arc};
+
+use crate::{
+    compiler::{CompileResult, LineInfo, LocalVar},
+    value::Value,
+    vm::*,
+};
+
+struct Instruction {
+    // Address before optimizing.
+    address: usize,
+    op_code: u8,
+    operands: Vec<u8>,
+    // Index of the instruction a jump lands on, resolved from the byte
+    // address once while decoding so that it survives removals.
+    jump_target: Option<usize>,
+    removed: bool,
+}
+
+/// Bytecode the optimizer can't decode. `address` is the offset of the
+/// offending instruction inside the bytecode of `function`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptimizeError {
+    pub function: String,
+    pub address: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OptimizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:04x}: {}",
+            self.function, self.address, self.message
+        )
+    }
+}
+
+pub fn optimize(result: &mut CompileResult) -> Result<(), OptimizeError> {
+    optimize_function(
+        "main",
+        &mut result.bytecode,
+        &mut result.constants,
+        &mut result.locals,
+        &mut result.lines,
+    )
+}
+
+fn optimize_function(
+    name: &str,
+    bytecode: &mut Vec<u8>,
+    constants: &mut [Value],
+    locals: &mut [LocalVar],
+    lines: &mut Vec<LineInfo>,
+) -> Result<(), OptimizeError> {
+    for constant in constants.iter_mut() {
+        if let Value::Function { val: function } = constant {
+            let function = Arc::make_mut(function);
+            optimize_function(
+                &function.name,
+                &mut function.bytecode,
+                &mut function.constants,
+                &mut function.locals,
+                &mut function.lines,
+            )?;
+        }
+    }
+
+    let mut instructions = decode(bytecode).map_err(|(address, message)| OptimizeError {
+        function: name.to_string(),
+        address,
+        message,
+    })?;
+
+    while peephole(constants, &mut instructions) {}
+
+    let addresses;
+    (*bytecode, addresses) = encode(&instructions);
+
+    // An address whose instruction was removed now refers to the next
+    // instruction that survived.
+    let relocate = |address: usize| {
+        let index = instructions
+            .binary_search_by_key(&address, |instruction| instruction.address)
+            .unwrap_or(instructions.len());
+        addresses[index]
+    };
+
+    for local in locals.iter_mut() {
+        local.start = relocate(local.start);
+        local.end = relocate(local.end);
+    }
+
+    // If several lines now start at the same address, the later line wins.
+    let mut relocated_lines: Vec<LineInfo> = vec![];
+    for line in lines.iter() {
+        let address = relocate(line.address);
+
+        match relocated_lines.last_mut() {
+            Some(last) if last.address == address => last.line = line.line,
+            _ => relocated_lines.push(LineInfo {
+                address,
+                line: line.line,
+            }),
+        }
+    }
+    *lines = relocated_lines;
+
+    Ok(())
+}
+
+// Applies every rewrite it finds in one pass and reports whether there was
+// any, as one rewrite can make room for another.
+fn peephole(constants: &[Value], instructions: &mut [Instruction]) -> bool {
+    let mut targets = jump_targets(instructions);
+    let mut changed = false;
+
+    let mut i = 0;
+    while i < instructions.len() {
+        if instructions[i].removed {
+            i += 1;
+            continue;
+        }
+
+        let next = next_live(instructions, i + 1);
+
+        match instructions[i].op_code {
+            // A constant that is immediately discarded has no effect.
+            OP_CONST => {
+                if let Some(next) = next {
+                    if instructions[next].op_code == OP_POP && !targets[next] {
+                        remove(instructions, &mut targets, i);
+                        remove(instructions, &mut targets, next);
+                        changed = true;
+                    }
+                }
+            }
+            // Jumping to the following instruction is the same as falling through.
+            OP_JUMP => {
+                let target = next_live(instructions, instructions[i].jump_target.unwrap());
+                if target == next {
+                    remove(instructions, &mut targets, i);
+                    changed = true;
+                }
+            }
+            // Popping the result and pushing it back again is a no-op.
+            OP_SCOPE_EXIT if instructions[i].operands[0] == 0 => {
+                remove(instructions, &mut targets, i);
+                changed = true;
+            }
+            // GET_VAR x; CONST c; ADD; SET_VAR x  =>  INC_VAR x c
+            OP_GET_VAR => {
+                if let Some([constant, add, set]) = next_three(instructions, i) {
+                    let slot = instructions[i].operands[0];
+                    let is_increment = instructions[constant].op_code == OP_CONST
+                        && instructions[add].op_code == OP_ADD
+                        && instructions[set].op_code == OP_SET_VAR
+                        && instructions[set].operands[0] == slot
+                        && !targets[constant]
+                        && !targets[add]
+                        && !targets[set]
+                        && matches!(
+                            constants[instructions[constant].operands[0] as usize],
+                            Value::Number { .. }
+                        );
+
+                    if is_increment {
+                        let constant_index = instructions[constant].operands[0];
+                        instructions[i].op_code = OP_INC_VAR;
+                        instructions[i].operands = vec![slot, constant_index];
+                        remove(instructions, &mut targets, constant);
+                        remove(instructions, &mut targets, add);
+                        remove(instructions, &mut targets, set);
+                        changed = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        i += 1;
+    }
+
+    changed
+}
+
+// Jumps to a removed instruction land on the next one that survives, which
+// later rewrites in the same pass have to treat as a target too.
+fn remove(instructions: &mut [Instruction], targets: &mut [bool], index: usize) {
+    instructions[index].removed = true;
+    if targets[index] {
+        if let Some(next) = next_live(instructions, index + 1) {
+            targets[next] = true;
+        }
+    }
+}
+
+fn next_live(instructions: &[Instruction], from: usize) -> Option<usize> {
+    (from..instructions.len()).find(|&index| !instructions[index].removed)
+}
+
+fn next_three(instructions: &[Instruction], index: usize) -> Option<[usize; 3]> {
+    let first = next_live(instructions, index + 1)?;
+    let second = next_live(instructions, first + 1)?;
+    let third = next_live(instructions, second + 1)?;
+    Some([first, second, third])
+}
+
+fn jump_targets(instructions: &[Instruction]) -> Vec<bool> {
+    let mut targets = vec![false; instructions.len()];
+
+    for instruction in instructions
+        .iter()
+        .filter(|instruction| !instruction.removed)
+    {
+        if let Some(target) = instruction.jump_target {
+            if let Some(target) = next_live(instructions, target) {
+                targets[target] = true;
+            }
+        }
+    }
+
+    targets
+}
+
+// Errors carry the address of the instruction that couldn't be decoded.
+fn decode(bytecode: &[u8]) -> Result<Vec<Instruction>, (usize, String)> {
+    let mut instructions = vec![];
+    let mut addresses = vec![];
+
+    let mut ip = 0;
+    while ip < bytecode.len() {
+        let op_code = bytecode[ip];
+        let length = operand_count(op_code)
+            .ok_or_else(|| (ip, format!("Invalid instruction {:02x}", op_code)))?;
+        if ip + length >= bytecode.len() {
+            return Err((ip, String::from("Instruction is missing its operands")));
+        }
+
+        addresses.push(ip);
+        instructions.push(Instruction {
+            address: ip,
+            op_code,
+            operands: bytecode[ip + 1..ip + 1 + length].to_vec(),
+            jump_target: None,
+            removed: false,
+        });
+
+        ip += 1 + length;
+    }
+
+    for instruction in instructions.iter_mut() {
+        if is_jump(instruction.op_code) {
+            let address = decode_address(&instruction.operands);
+            // Jumping to the end of the bytecode is allowed, so the target
+            // may be one past the last instruction.
+            let target = match addresses.binary_search(&address) {
+                Ok(target) => target,
+                Err(_) if address == bytecode.len() => addresses.len(),
+                Err(_) => {
+                    return Err((
+                        instruction.address,
+                        format!("Invalid jump target {:04x}", address),
+                    ))
+                }
+            };
+            instruction.jump_target = Some(target);
+        }
+    }
+
+    Ok(instructions)
+}
+
+/// Returns the bytecode along with the new address of every instruction;
+/// removed instructions take the address of the next one that survives.
+fn encode(instructions: &[Instruction]) -> (Vec<u8>, Vec<usize>) {
+    let mut addresses = vec![0; instructions.len() + 1];
+    let mut address = 0;
+    for (index, instruction) in instructions.iter().enumerate() {
+        addresses[index] = address;
+        if !instruction.removed {
+            address += 1 + instruction.operands.len();
+        }
+    }
+    addresses[instructions.len()] = address;
+
+    let mut bytecode = vec![];
+    for instruction in instructions
+        .iter()
+        .filter(|instruction| !instruction.removed)
+    {
+        bytecode.push(instruction.op_code);
+        match instruction.jump_target {
+            // Removing instructions only moves targets closer, so they still fit.
+            Some(target) => bytecode.extend(encode_address(addresses[target]).unwrap()),
+            None => bytecode.extend_from_slice(&instruction.operands),
+        }
+    }
+
+    (bytecode, addresses)
+}
@@ -0,0 +1,184 @@
+use super::{
+    hidden_name,
+    reader::{Datum, DatumKind},
+};
+
+// Forms defined in terms of other special forms. Each takes the items of
+// the form, head included, and returns the datum to parse instead.
+
+/// `(when test body...)` runs the body when `test` holds, else is `false`.
+pub fn when(items: Vec<Datum>, line: usize) -> Datum {
+    let (test, body) = test_and_body(items, line);
+    list(
+        vec![symbol("if", line), test, body, boolean(false, line)],
+        line,
+    )
+}
+
+/// `(unless test body...)` runs the body when `test` does not hold.
+pub fn unless(items: Vec<Datum>, line: usize) -> Datum {
+    let (test, body) = test_and_body(items, line);
+    list(
+        vec![symbol("if", line), test, boolean(false, line), body],
+        line,
+    )
+}
+
+/// `(cond (test body...)... (else body...))` becomes a chain of `if`s. It
+/// is `false` when no clause matches.
+pub fn cond(items: Vec<Datum>, line: usize) -> Datum {
+    let clauses: Vec<(Option<Datum>, Datum)> = items
+        .into_iter()
+        .skip(1)
+        .map(|clause| {
+            let clause_line = clause.line;
+            let DatumKind::List(mut items) = clause.kind else {
+                panic!("Expected a cond clause on line {}", clause_line);
+            };
+            if items.len() < 2 {
+                panic!(
+                    "cond clause needs a test and a body on line {}",
+                    clause_line
+                );
+            }
+
+            let test = items.remove(0);
+            let test = if test.as_symbol() == Some("else") {
+                None
+            } else {
+                Some(test)
+            };
+            (test, body(items, clause_line))
+        })
+        .collect();
+
+    chain(clauses, "cond", line)
+}
+
+/// `(case key ((value...) body...)... (else body...))` evaluates `key` once
+/// and runs the first clause listing a value `=` to it. Values are literals,
+/// names among them are symbols.
+pub fn case(items: Vec<Datum>, line: usize) -> Datum {
+    let mut items = items.into_iter().skip(1);
+    let Some(key) = items.next() else {
+        panic!("case needs a key on line {}", line);
+    };
+    let name = hidden_name();
+
+    let clauses = items
+        .map(|clause| {
+            let clause_line = clause.line;
+            let DatumKind::List(mut items) = clause.kind else {
+                panic!("Expected a case clause on line {}", clause_line);
+            };
+            if items.len() < 2 {
+                panic!(
+                    "case clause needs values and a body on line {}",
+                    clause_line
+                );
+            }
+
+            let values = items.remove(0);
+            let test = match values.kind {
+                DatumKind::Symbol(ref else_name) if else_name == "else" => None,
+                DatumKind::List(values) if !values.is_empty() => Some(any_equal(&name, values)),
+                _ => panic!("Expected a list of values on line {}", values.line),
+            };
+            (test, body(items, clause_line))
+        })
+        .collect();
+
+    let binding = list(vec![symbol(&name, line), key], line);
+    list(
+        vec![
+            symbol("let", line),
+            list(vec![binding], line),
+            chain(clauses, "case", line),
+        ],
+        line,
+    )
+}
+
+/// `(if test1 body1 (if test2 body2 ... else))`, where a clause without a
+/// test is the `else` and has to come last.
+fn chain(clauses: Vec<(Option<Datum>, Datum)>, name: &str, line: usize) -> Datum {
+    let mut result = boolean(false, line);
+    let last = clauses.len().saturating_sub(1);
+
+    for (index, (test, body)) in clauses.into_iter().enumerate().rev() {
+        result = match test {
+            Some(test) => list(vec![symbol("if", line), test, body, result], line),
+            None if index == last => body,
+            None => panic!("else has to be the last {} clause on line {}", name, line),
+        };
+    }
+
+    result
+}
+
+/// `(if (= name 'v1) true (if (= name 'v2) true ...))`.
+fn any_equal(name: &str, values: Vec<Datum>) -> Datum {
+    let mut result = None;
+
+    for value in values.into_iter().rev() {
+        let line = value.line;
+        let quoted = list(vec![symbol("quote", line), value], line);
+        let test = list(vec![symbol("=", line), symbol(name, line), quoted], line);
+
+        result = Some(match result {
+            None => test,
+            Some(rest) => list(
+                vec![symbol("if", line), test, boolean(true, line), rest],
+                line,
+            ),
+        });
+    }
+
+    result.unwrap()
+}
+
+fn test_and_body(items: Vec<Datum>, line: usize) -> (Datum, Datum) {
+    let name = items[0].as_symbol().unwrap_or_default().to_string();
+    let mut items = items.into_iter().skip(1);
+
+    let Some(test) = items.next() else {
+        panic!("{} needs a test on line {}", name, line);
+    };
+    let body: Vec<Datum> = items.collect();
+    if body.is_empty() {
+        panic!("{} needs a body on line {}", name, line);
+    }
+
+    (test, self::body(body, line))
+}
+
+/// One expression as is, several in a `begin`.
+fn body(mut data: Vec<Datum>, line: usize) -> Datum {
+    if data.len() == 1 {
+        return data.remove(0);
+    }
+
+    data.insert(0, symbol("begin", line));
+    list(data, line)
+}
+
+fn symbol(name: &str, line: usize) -> Datum {
+    Datum {
+        kind: DatumKind::Symbol(name.to_string()),
+        line,
+    }
+}
+
+fn boolean(value: bool, line: usize) -> Datum {
+    Datum {
+        kind: DatumKind::Boolean(value),
+        line,
+    }
+}
+
+fn list(items: Vec<Datum>, line: usize) -> Datum {
+    Datum {
+        kind: DatumKind::List(items),
+        line,
+    }
+}
@@ -0,0 +1,285 @@
+use std::{collections::HashMap, time::Instant};
+
+use crate::{
+    compiler::Compiler,
+    value::Value,
+    vm::{OP_CALL, OP_CONST, OP_HALT, VM},
+};
+
+use super::{
+    block, is_form,
+    reader::{Datum, DatumKind},
+    AstNode,
+};
+
+// Expansions that keep producing macro calls are cut off at this depth
+// instead of overflowing the host's stack.
+const MAX_EXPANSION_DEPTH: usize = 256;
+
+struct Macro {
+    arity: usize,
+    // The compiled transformer, called with the unevaluated arguments.
+    function: Value,
+}
+
+/// Rewrites macro calls in the reader's data before the parser gives it any
+/// meaning. `(defmacro name (params) body...)` compiles `body` like a
+/// function that is run at compile time on its arguments as quoted data;
+/// the datum it returns replaces the call and is expanded again.
+#[derive(Default)]
+pub struct MacroExpander {
+    macros: HashMap<String, Macro>,
+    vm: VM,
+    depth: usize,
+}
+
+impl MacroExpander {
+    pub fn new() -> MacroExpander {
+        MacroExpander::default()
+    }
+
+    /// Stops transformers that run too long, like `VM::set_fuel` and
+    /// `VM::set_deadline`. The fuel is shared by all expansions.
+    pub fn set_limits(&mut self, fuel: Option<u64>, deadline: Option<Instant>) {
+        self.vm.set_fuel(fuel);
+        self.vm.set_deadline(deadline);
+    }
+
+    /// Expands the top-level forms of a program. Macro definitions are
+    /// removed; each one applies to the forms after it.
+    pub fn expand_program(&mut self, data: Vec<Datum>) -> Vec<Datum> {
+        let mut expanded = vec![];
+
+        for datum in data {
+            if is_form(&datum, "defmacro") {
+                self.define(datum);
+            } else {
+                expanded.push(self.expand(datum));
+            }
+        }
+
+        expanded
+    }
+
+    pub fn expand(&mut self, datum: Datum) -> Datum {
+        let line = datum.line;
+        let DatumKind::List(items) = datum.kind else {
+            return datum;
+        };
+
+        let head = items.first().and_then(Datum::as_symbol).map(String::from);
+        let items = match head.as_deref() {
+            Some("quote") => items,
+            Some("quasiquote") => items
+                .into_iter()
+                .map(|item| self.expand_template(item, 1))
+                .collect(),
+            // The parameter list is not a call.
+            Some("def") => items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| if index == 2 { item } else { self.expand(item) })
+                .collect(),
+            // Names being bound are not calls, only their values are expanded.
+            Some("let" | "let*" | "letrec") => items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    if index == 1 {
+                        self.expand_items(item, 0, Self::expand_rest)
+                    } else {
+                        self.expand(item)
+                    }
+                })
+                .collect(),
+            Some("for") => items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    if index == 1 {
+                        self.expand_rest(item)
+                    } else {
+                        self.expand(item)
+                    }
+                })
+                .collect(),
+            // Match patterns and case values are data, not calls.
+            Some("match" | "case") => items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    if index >= 2 {
+                        self.expand_rest(item)
+                    } else {
+                        self.expand(item)
+                    }
+                })
+                .collect(),
+            Some("try") => items
+                .into_iter()
+                .map(|item| {
+                    if is_form(&item, "catch") || is_form(&item, "finally") {
+                        self.expand_rest(item)
+                    } else {
+                        self.expand(item)
+                    }
+                })
+                .collect(),
+            Some(name) if self.macros.contains_key(name) => {
+                let expansion = self.expand_call(name, items, line);
+
+                self.depth += 1;
+                if self.depth > MAX_EXPANSION_DEPTH {
+                    panic!("Expansion of macro {} is too deep on line {}", name, line);
+                }
+                let expansion = self.expand(expansion);
+                self.depth -= 1;
+
+                return expansion;
+            }
+            _ => items.into_iter().map(|item| self.expand(item)).collect(),
+        };
+
+        Datum {
+            kind: DatumKind::List(items),
+            line,
+        }
+    }
+
+    /// Expands every item of a list but the first, e.g. the value of a
+    /// binding or the guard and body of a match clause.
+    fn expand_rest(&mut self, datum: Datum) -> Datum {
+        self.expand_items(datum, 1, Self::expand)
+    }
+
+    /// Applies `expand` to the items of a list after the first `skip`.
+    fn expand_items(
+        &mut self,
+        datum: Datum,
+        skip: usize,
+        expand: fn(&mut Self, Datum) -> Datum,
+    ) -> Datum {
+        let line = datum.line;
+        let DatumKind::List(items) = datum.kind else {
+            return datum;
+        };
+
+        let items = items
+            .into_iter()
+            .enumerate()
+            .map(|(index, item)| {
+                if index < skip {
+                    item
+                } else {
+                    expand(self, item)
+                }
+            })
+            .collect();
+        Datum {
+            kind: DatumKind::List(items),
+            line,
+        }
+    }
+
+    /// Expands only the parts of a quasiquote template that get evaluated,
+    /// the unquotes at `depth` 1.
+    fn expand_template(&mut self, datum: Datum, depth: usize) -> Datum {
+        let line = datum.line;
+        let DatumKind::List(items) = datum.kind else {
+            return datum;
+        };
+
+        let head = items.first().and_then(Datum::as_symbol);
+        let is_unquote = matches!(head, Some("unquote" | "unquote-splicing"));
+        let items = if is_unquote && depth == 1 {
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| if index == 0 { item } else { self.expand(item) })
+                .collect()
+        } else {
+            let depth = match head {
+                Some("quasiquote") => depth + 1,
+                _ if is_unquote => depth - 1,
+                _ => depth,
+            };
+            items
+                .into_iter()
+                .map(|item| self.expand_template(item, depth))
+                .collect()
+        };
+
+        Datum {
+            kind: DatumKind::List(items),
+            line,
+        }
+    }
+
+    fn define(&mut self, datum: Datum) {
+        let line = datum.line;
+        let DatumKind::List(mut items) = datum.kind else {
+            unreachable!();
+        };
+
+        let name = match items.get(1).and_then(Datum::as_symbol) {
+            Some(name) => name.to_string(),
+            None => panic!("defmacro needs a name on line {}", line),
+        };
+        let arity = match items.get(2).map(|parameters| &parameters.kind) {
+            Some(DatumKind::List(parameters)) => parameters.len(),
+            _ => panic!("Expected a parameter list on line {}", line),
+        };
+
+        // The transformer is an ordinary function; compiling a program that
+        // only declares it evaluates to the function itself.
+        items[0].kind = DatumKind::Symbol(String::from("def"));
+        let declaration = self.expand(Datum {
+            kind: DatumKind::List(items),
+            line,
+        });
+
+        let mut compiler = Compiler::new(false);
+        compiler.compile(AstNode::Program {
+            children: vec![block(vec![declaration])],
+        });
+        let function = self
+            .vm
+            .exec(compiler.result.constants, compiler.result.bytecode)
+            .unwrap_or_else(|err| panic!("Macro {} failed on line {}: {}", name, line, err));
+
+        self.macros.insert(name, Macro { arity, function });
+    }
+
+    fn expand_call(&mut self, name: &str, items: Vec<Datum>, line: usize) -> Datum {
+        let transformer = &self.macros[name];
+
+        let arguments = items.len() - 1;
+        if arguments != transformer.arity {
+            panic!(
+                "Macro {} expects {} arguments, got {} on line {}",
+                name, transformer.arity, arguments, line
+            );
+        }
+        if arguments > u8::MAX as usize - 1 {
+            panic!("Too many macro arguments on line {}", line);
+        }
+
+        let mut constants = vec![transformer.function.clone()];
+        constants.extend(items.iter().skip(1).map(Value::from));
+
+        let mut bytecode = vec![];
+        for index in 0..constants.len() {
+            bytecode.extend([OP_CONST, index as u8]);
+        }
+        bytecode.extend([OP_CALL, arguments as u8, OP_HALT]);
+
+        let expansion = self
+            .vm
+            .exec(constants, bytecode)
+            .unwrap_or_else(|err| panic!("Macro {} failed on line {}: {}", name, line, err));
+
+        expansion
+            .to_datum(line)
+            .unwrap_or_else(|| panic!("Macro {} returned {} on line {}", name, expansion, line))
+    }
+}
@@ -1,5 +1,20 @@
-use self::tokenizer::{CurrentToken, TokenKind, Tokenizer};
-
+use std::{
+    mem,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use crate::value::Value;
+
+use self::{
+    macros::MacroExpander,
+    reader::{Datum, DatumKind, Reader},
+};
+
+mod derived;
+pub mod macros;
+pub mod reader;
 pub mod tokenizer;
 
 #[derive(Debug, Clone)]
@@ -20,6 +35,44 @@ pub enum BinaryExpressionType {
     Lesser,
     LesserEqual,
     Equal,
+    // `(nth list index)`
+    Nth,
+    // `(drop list count)`, the list without its first `count` elements.
+    Drop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LetType {
+    // Values see none of the bindings.
+    Let,
+    // Each value sees the bindings before it.
+    Sequential,
+    // Functions see each other, values see the functions.
+    Recursive,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    // `_`, matches anything.
+    Wildcard,
+    // A name, matches anything and binds it.
+    Variable(String),
+    // Numbers, strings, booleans and quoted atoms, compared with `=`.
+    Literal(Value),
+    // `(p...)`, or `(p... . rest)` where `rest` matches the list of the
+    // remaining elements.
+    List {
+        elements: Vec<Pattern>,
+        rest: Option<Box<Pattern>>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchClause {
+    pub pattern: Pattern,
+    // Has to hold too, with the pattern's names bound.
+    pub guard: Option<AstNode>,
+    pub body: AstNode,
 }
 
 #[derive(Debug, Clone)]
@@ -44,6 +97,21 @@ pub enum AstNode {
     WhileExpression {
         condition: Box<AstNode>,
         body: Box<AstNode>,
+        // Runs after the body and on `continue`, before the next test.
+        update: Option<Box<AstNode>>,
+    },
+    // Leaves the innermost loop, which then evaluates to `value`.
+    Break {
+        value: Option<Box<AstNode>>,
+    },
+    // Skips to the innermost loop's update and test.
+    Continue,
+    LengthExpression {
+        list: Box<AstNode>,
+    },
+    // The type of `value` as a symbol, like `number` or `list`.
+    TypeOfExpression {
+        value: Box<AstNode>,
     },
     Identifier {
         name: String,
@@ -58,6 +126,8 @@ pub enum AstNode {
     },
     Block {
         children: Vec<AstNode>,
+        // Source line each child starts on.
+        lines: Vec<usize>,
     },
     FunctionDeclaration {
         identifier: Box<AstNode>,
@@ -68,247 +138,864 @@ pub enum AstNode {
         identifier: Box<AstNode>,
         parameters: Vec<AstNode>,
     },
+    // A datum used as a value, see `Value::from`.
+    Quote {
+        datum: Datum,
+    },
+    // A new list of the values of `elements`.
+    ListExpression {
+        elements: Vec<AstNode>,
+    },
+    // The elements of all `lists` in one new list.
+    ConcatExpression {
+        lists: Vec<AstNode>,
+    },
+    // A fresh symbol, see `Symbol::uninterned`.
+    Gensym,
+    LetExpression {
+        r#type: LetType,
+        // Variable declarations, and function declarations for `letrec`.
+        bindings: Vec<AstNode>,
+        body: Vec<AstNode>,
+        // Source line each body expression starts on.
+        lines: Vec<usize>,
+    },
+    // Unwinds to the innermost `try` with `value`.
+    Throw {
+        value: Box<AstNode>,
+    },
+    // `catch` binds the thrown value to a name for its handler. `finally`
+    // runs however the body or handler is left; its value is dropped.
+    TryExpression {
+        body: Box<AstNode>,
+        catch: Option<(String, Box<AstNode>)>,
+        finally: Option<Box<AstNode>>,
+    },
+    // Runs the first clause whose pattern matches `value`.
+    MatchExpression {
+        value: Box<AstNode>,
+        clauses: Vec<MatchClause>,
+    },
+    // Declares the exports of the module at `path` as `name/export`.
+    Import {
+        path: String,
+        name: String,
+        line: usize,
+    },
+    // The names a module makes available to the files importing it.
+    Export {
+        names: Vec<String>,
+        line: usize,
+    },
 }
 
+/// Turns source code into an AST in three steps: the `Reader` produces
+/// generic `Datum` trees, the `MacroExpander` rewrites macro calls in them,
+/// then special forms are recognized by the symbol at the head of each list.
 pub struct Parser {
-    pub tokenizer: Tokenizer,
-    prev_token: Option<CurrentToken>,
-    cur_token: Option<CurrentToken>,
-}
-
-enum SetVariableType {
-    Declare,
-    Set,
+    reader: Reader,
+    expander: MacroExpander,
+    is_wrapped: bool,
 }
 
 impl Parser {
     pub fn new(source_code: String) -> Parser {
-        let source_code_block = format!("(begin {})", source_code);
-
         Parser {
-            tokenizer: Tokenizer::new(source_code_block),
-            prev_token: None,
-            cur_token: None,
+            reader: Reader::new(source_code),
+            expander: MacroExpander::new(),
+            is_wrapped: false,
         }
     }
 
+    /// Reads the program as one list holding all top-level forms, the way
+    /// older programs are written.
+    pub fn set_wrapped(&mut self, is_wrapped: bool) {
+        self.is_wrapped = is_wrapped;
+    }
+
+    /// Stops macro transformers that run too long, see
+    /// `MacroExpander::set_limits`.
+    pub fn set_limits(&mut self, fuel: Option<u64>, deadline: Option<Instant>) {
+        self.expander.set_limits(fuel, deadline);
+    }
+
     pub fn parse(&mut self) -> AstNode {
+        let data = self.expand();
+
+        if data.is_empty() {
+            panic!("Empty program");
+        }
+
         AstNode::Program {
-            children: self.expressions(&TokenKind::EndOfFile),
+            children: vec![block(data)],
         }
     }
 
-    fn expressions(&mut self, end_type: &TokenKind) -> Vec<AstNode> {
-        let mut expressions: Vec<AstNode> = vec![];
-        loop {
-            if let Some(cur_token) = self.cur_token.as_ref() {
-                if cur_token.kind != TokenKind::OpenParen {
-                    self.gen_next_token();
-
-                    if self.cur_token.as_ref().unwrap().kind != TokenKind::OpenParen {
-                        panic!("Invalid token");
-                    }
-                }
-            }
+    /// The top-level forms with all macros expanded.
+    pub fn expand(&mut self) -> Vec<Datum> {
+        let mut data = self.reader.read_all();
+
+        if self.is_wrapped {
+            data = match <[Datum; 1]>::try_from(data) {
+                Ok(
+                    [Datum {
+                        kind: DatumKind::List(items),
+                        ..
+                    }],
+                ) => items,
+                _ => panic!("A wrapped program must be a single list of forms"),
+            };
+        }
 
-            let statement = self.expression();
-            expressions.push(statement);
+        self.expander.expand_program(data)
+    }
+}
 
-            self.gen_next_token();
+fn expression(datum: Datum) -> AstNode {
+    match datum.kind {
+        DatumKind::Number(value) => AstNode::Literal {
+            r#type: LiteralType::Number,
+            value: value.to_string(),
+        },
+        DatumKind::String(value) => AstNode::Literal {
+            r#type: LiteralType::String,
+            value,
+        },
+        DatumKind::Boolean(value) => AstNode::Literal {
+            r#type: LiteralType::Boolean,
+            value: value.to_string(),
+        },
+        DatumKind::Symbol(name) => AstNode::Identifier { name },
+        DatumKind::List(items) => list(items, datum.line),
+    }
+}
 
-            match &self.cur_token.as_ref().unwrap().kind {
-                kind if kind == end_type => break expressions,
-                TokenKind::OpenParen => {
-                    continue;
-                }
-                TokenKind::CloseParen => {
-                    // handle the situation where the last token is an identifier or a literal
-                    self.gen_next_token();
+fn list(items: Vec<Datum>, line: usize) -> AstNode {
+    let Some(head) = items.first() else {
+        panic!("Empty list on line {}", line);
+    };
+
+    let binary_type = match head.as_symbol() {
+        Some("+") => Some(BinaryExpressionType::Add),
+        Some("-") => Some(BinaryExpressionType::Sub),
+        Some("*") => Some(BinaryExpressionType::Mul),
+        Some("/") => Some(BinaryExpressionType::Div),
+        Some(">") => Some(BinaryExpressionType::Greater),
+        Some(">=") => Some(BinaryExpressionType::GreaterEqual),
+        Some("<") => Some(BinaryExpressionType::Lesser),
+        Some("<=") => Some(BinaryExpressionType::LesserEqual),
+        Some("=") => Some(BinaryExpressionType::Equal),
+        Some("nth") => Some(BinaryExpressionType::Nth),
+        Some("drop") => Some(BinaryExpressionType::Drop),
+        _ => None,
+    };
+    if let Some(r#type) = binary_type {
+        let [left, right] = arguments(items, line);
+        return AstNode::BinaryExpression {
+            r#type,
+            left: Box::new(expression(left)),
+            right: Box::new(expression(right)),
+        };
+    }
 
-                    if &self.cur_token.clone().unwrap().kind == end_type {
-                        break expressions;
-                    }
-                    continue;
-                }
-                _ => {
-                    panic!("Invalid token");
+    match head.as_symbol() {
+        Some("if") => {
+            let [condition, consequent, alternate] = arguments(items, line);
+            AstNode::IfExpression {
+                condition: Box::new(expression(condition)),
+                consequent: Box::new(expression(consequent)),
+                alternate: Box::new(expression(alternate)),
+            }
+        }
+        Some("while") => {
+            let [condition, body] = arguments(items, line);
+            AstNode::WhileExpression {
+                condition: Box::new(expression(condition)),
+                body: Box::new(expression(body)),
+                update: None,
+            }
+        }
+        Some("for") => for_expression(items, line),
+        Some("for-each") => for_each_expression(items, line),
+        Some("break") => match <[Datum; 1]>::try_from(items) {
+            Ok([_]) => AstNode::Break { value: None },
+            Err(items) => {
+                let [value] = arguments(items, line);
+                AstNode::Break {
+                    value: Some(Box::new(expression(value))),
                 }
             }
+        },
+        Some("continue") => {
+            let [] = arguments(items, line);
+            AstNode::Continue
+        }
+        Some("length") => {
+            let [list] = arguments(items, line);
+            AstNode::LengthExpression {
+                list: Box::new(expression(list)),
+            }
+        }
+        Some("throw") => {
+            let [value] = arguments(items, line);
+            AstNode::Throw {
+                value: Box::new(expression(value)),
+            }
+        }
+        Some("try") => try_expression(items, line),
+        Some("catch" | "finally") => panic!(
+            "{} outside of try on line {}",
+            head.as_symbol().unwrap_or_default(),
+            line
+        ),
+        Some("type-of") => {
+            let [value] = arguments(items, line);
+            AstNode::TypeOfExpression {
+                value: Box::new(expression(value)),
+            }
+        }
+        Some("match") => match_expression(items, line),
+        Some("import") => import(items, line),
+        Some("export") => AstNode::Export {
+            names: items
+                .into_iter()
+                .skip(1)
+                .map(|item| match identifier(item) {
+                    AstNode::Identifier { name } => name,
+                    _ => unreachable!(),
+                })
+                .collect(),
+            line,
+        },
+        Some("var") => {
+            let [name, value] = arguments(items, line);
+            AstNode::VariableDeclaration {
+                identifier: Box::new(identifier(name)),
+                value: Box::new(expression(value)),
+            }
+        }
+        Some("set") => {
+            let [name, value] = arguments(items, line);
+            AstNode::SetVariable {
+                identifier: Box::new(identifier(name)),
+                value: Box::new(expression(value)),
+            }
+        }
+        Some("begin") if items.len() > 1 => block(items.into_iter().skip(1).collect()),
+        Some("begin") => panic!("begin needs at least one expression on line {}", line),
+        Some("def") => function_declaration(items, line),
+        Some("when") => expression(derived::when(items, line)),
+        Some("unless") => expression(derived::unless(items, line)),
+        Some("cond") => expression(derived::cond(items, line)),
+        Some("case") => expression(derived::case(items, line)),
+        Some("let") => let_expression(LetType::Let, items, line),
+        Some("let*") => let_expression(LetType::Sequential, items, line),
+        Some("letrec") => let_expression(LetType::Recursive, items, line),
+        Some("call") if items.len() > 1 => call_expression(items.into_iter().skip(1).collect()),
+        Some("call") => panic!("call needs a function on line {}", line),
+        Some("quote") => {
+            let [datum] = arguments(items, line);
+            AstNode::Quote { datum }
         }
+        Some("quasiquote") => {
+            let [datum] = arguments(items, line);
+            quasiquote(datum, 1)
+        }
+        Some("gensym") => {
+            let [] = arguments(items, line);
+            AstNode::Gensym
+        }
+        Some("defmacro") => panic!("defmacro is only allowed at the top level on line {}", line),
+        Some(name @ ("unquote" | "unquote-splicing")) => {
+            panic!("{} outside of quasiquote on line {}", name, line)
+        }
+        _ => call_expression(items),
     }
+}
 
-    fn expression(&mut self) -> AstNode {
-        self.gen_next_token();
+/// `(def name (params...) body...)`. Several body expressions, or one that
+/// is not a `begin`, are wrapped in a block.
+fn function_declaration(items: Vec<Datum>, line: usize) -> AstNode {
+    let mut items = items.into_iter().skip(1);
+
+    let (Some(name), Some(parameters)) = (items.next(), items.next()) else {
+        panic!("def needs a name and a parameter list on line {}", line);
+    };
+    let DatumKind::List(parameters) = parameters.kind else {
+        panic!("Expected a parameter list on line {}", parameters.line);
+    };
+
+    let body: Vec<Datum> = items.collect();
+    let body = match body.as_slice() {
+        [] => panic!("def needs a body on line {}", line),
+        [single] if is_form(single, "begin") => expression(single.clone()),
+        _ => block(body),
+    };
+
+    AstNode::FunctionDeclaration {
+        identifier: Box::new(identifier(name)),
+        parameters: parameters.into_iter().map(identifier).collect(),
+        body: Box::new(body),
+    }
+}
 
-        if let Some(CurrentToken { kind, value }) = self.cur_token.clone() {
-            match kind {
-                TokenKind::OpenParen => self.expression(),
-                TokenKind::Add => self.binary_expression(BinaryExpressionType::Add),
-                TokenKind::Sub => self.binary_expression(BinaryExpressionType::Sub),
-                TokenKind::Mul => self.binary_expression(BinaryExpressionType::Mul),
-                TokenKind::Div => self.binary_expression(BinaryExpressionType::Div),
-                TokenKind::Greater => self.binary_expression(BinaryExpressionType::Greater),
-                TokenKind::GreaterEqual => {
-                    self.binary_expression(BinaryExpressionType::GreaterEqual)
+/// `(let ((name value)...) body...)`. `letrec` also takes `def` forms as
+/// bindings.
+fn let_expression(r#type: LetType, items: Vec<Datum>, line: usize) -> AstNode {
+    let name = items[0].as_symbol().unwrap_or_default().to_string();
+    let mut items = items.into_iter().skip(1);
+
+    let Some(Datum {
+        kind: DatumKind::List(bindings),
+        ..
+    }) = items.next()
+    else {
+        panic!("{} needs a binding list on line {}", name, line);
+    };
+
+    let bindings = bindings
+        .into_iter()
+        .map(|binding| {
+            let binding_line = binding.line;
+            match binding.kind {
+                DatumKind::List(items) if r#type == LetType::Recursive && is_def(&items) => {
+                    function_declaration(items, binding_line)
                 }
-                TokenKind::Lesser => self.binary_expression(BinaryExpressionType::Lesser),
-                TokenKind::LesserEqual => self.binary_expression(BinaryExpressionType::LesserEqual),
-                TokenKind::Equal => self.binary_expression(BinaryExpressionType::Equal),
-                TokenKind::NumberLiteral => self.literal(LiteralType::Number, value),
-                TokenKind::StringLiteral => self.literal(LiteralType::String, value),
-                TokenKind::BooleanLiteral => self.literal(LiteralType::Boolean, value),
-                TokenKind::VariableDeclaration => self.set_variable(SetVariableType::Declare),
-                TokenKind::SetVariable => self.set_variable(SetVariableType::Set),
-                TokenKind::BeginBlock => self.block(),
-                TokenKind::Identifier => self.identifier(value),
-                TokenKind::While => self.while_expression(),
-                TokenKind::If => self.if_expression(),
-                TokenKind::FunctionDeclaration => self.function_declaration(),
-                TokenKind::CallFunction => self.call_expression(),
-                _ => {
-                    panic!("Invalid token");
+                DatumKind::List(items) if items.len() == 2 => {
+                    let [name, value]: [Datum; 2] = items.try_into().unwrap();
+                    AstNode::VariableDeclaration {
+                        identifier: Box::new(identifier(name)),
+                        value: Box::new(expression(value)),
+                    }
                 }
+                _ => panic!("Expected (name value) on line {}", binding_line),
             }
-        } else {
-            panic!("No token found");
-        }
+        })
+        .collect();
+
+    let body: Vec<Datum> = items.collect();
+    if body.is_empty() {
+        panic!("{} needs a body on line {}", name, line);
     }
 
-    fn call_expression(&mut self) -> AstNode {
-        let identifier = self.expression();
-        let mut parameters = vec![];
+    AstNode::LetExpression {
+        r#type,
+        bindings,
+        lines: body.iter().map(|datum| datum.line).collect(),
+        body: body.into_iter().map(expression).collect(),
+    }
+}
 
-        loop {
-            if self.tokenizer.lookahead().kind == TokenKind::CloseParen {
-                break;
-            }
-            parameters.push(self.expression());
+fn is_def(items: &[Datum]) -> bool {
+    items.first().and_then(Datum::as_symbol) == Some("def")
+}
+
+/// `(for (name start end [step]) body...)` counts `name` from `start` up to
+/// `end`, exclusive, by `step` or 1. A negative step counts down.
+fn for_expression(items: Vec<Datum>, line: usize) -> AstNode {
+    let mut items = items.into_iter().skip(1);
+
+    let Some(Datum {
+        kind: DatumKind::List(range),
+        ..
+    }) = items.next()
+    else {
+        panic!("for needs (name start end) on line {}", line);
+    };
+    let mut range = range.into_iter();
+    let (Some(name), Some(start), Some(end), step, None) = (
+        range.next(),
+        range.next(),
+        range.next(),
+        range.next(),
+        range.next(),
+    ) else {
+        panic!("for needs (name start end [step]) on line {}", line);
+    };
+    let name = identifier(name);
+
+    // Literal bounds are compared against directly, anything else is
+    // evaluated once into a hidden variable.
+    let mut bindings = vec![declaration(name.clone(), expression(start))];
+    let end = match end {
+        end @ Datum {
+            kind: DatumKind::Number(_),
+            ..
+        } => expression(end),
+        end => {
+            let end_name = hidden_name();
+            bindings.push(declaration(variable(&end_name), expression(end)));
+            variable(&end_name)
+        }
+    };
+
+    // A literal step fixes the direction, any other step picks it each time
+    // the bound is tested.
+    let (step, condition) = match step {
+        None => (
+            number(1.0),
+            binary(BinaryExpressionType::Lesser, name.clone(), end),
+        ),
+        Some(Datum {
+            kind: DatumKind::Number(step),
+            ..
+        }) => {
+            let test = if step < 0.0 {
+                BinaryExpressionType::Greater
+            } else {
+                BinaryExpressionType::Lesser
+            };
+            (number(step), binary(test, name.clone(), end))
+        }
+        Some(step) => {
+            let step_name = hidden_name();
+            bindings.push(declaration(variable(&step_name), expression(step)));
+            let condition = AstNode::IfExpression {
+                condition: Box::new(binary(
+                    BinaryExpressionType::Lesser,
+                    variable(&step_name),
+                    number(0.0),
+                )),
+                consequent: Box::new(binary(
+                    BinaryExpressionType::Greater,
+                    name.clone(),
+                    end.clone(),
+                )),
+                alternate: Box::new(binary(BinaryExpressionType::Lesser, name.clone(), end)),
+            };
+            (variable(&step_name), condition)
         }
+    };
+
+    let body = block(body(items, "for", line));
+    let update = AstNode::SetVariable {
+        identifier: Box::new(name.clone()),
+        value: Box::new(binary(BinaryExpressionType::Add, name.clone(), step)),
+    };
+
+    AstNode::LetExpression {
+        r#type: LetType::Sequential,
+        bindings,
+        body: vec![AstNode::WhileExpression {
+            condition: Box::new(condition),
+            body: Box::new(body),
+            update: Some(Box::new(update)),
+        }],
+        lines: vec![line],
+    }
+}
 
-        self.check_for_close_paren();
+/// `(for-each name list body...)` runs the body with `name` bound to each
+/// element of `list` in turn.
+fn for_each_expression(items: Vec<Datum>, line: usize) -> AstNode {
+    let mut items = items.into_iter().skip(1);
+
+    let (Some(name), Some(list)) = (items.next(), items.next()) else {
+        panic!("for-each needs a name and a list on line {}", line);
+    };
+    let name = identifier(name);
+    let [list_name, index_name, length_name] = [(); 3].map(|_| hidden_name());
+
+    let body = body(items, "for-each", line);
+    let element = AstNode::LetExpression {
+        r#type: LetType::Let,
+        bindings: vec![declaration(
+            name,
+            binary(
+                BinaryExpressionType::Nth,
+                variable(&list_name),
+                variable(&index_name),
+            ),
+        )],
+        lines: body.iter().map(|datum| datum.line).collect(),
+        body: body.into_iter().map(expression).collect(),
+    };
+    let update = AstNode::SetVariable {
+        identifier: Box::new(variable(&index_name)),
+        value: Box::new(binary(
+            BinaryExpressionType::Add,
+            variable(&index_name),
+            number(1.0),
+        )),
+    };
+
+    AstNode::LetExpression {
+        r#type: LetType::Sequential,
+        bindings: vec![
+            declaration(variable(&list_name), expression(list)),
+            declaration(variable(&index_name), number(0.0)),
+            declaration(
+                variable(&length_name),
+                AstNode::LengthExpression {
+                    list: Box::new(variable(&list_name)),
+                },
+            ),
+        ],
+        body: vec![AstNode::WhileExpression {
+            condition: Box::new(binary(
+                BinaryExpressionType::Lesser,
+                variable(&index_name),
+                variable(&length_name),
+            )),
+            body: Box::new(element),
+            update: Some(Box::new(update)),
+        }],
+        lines: vec![line],
+    }
+}
 
-        AstNode::CallExpression {
-            identifier: Box::new(identifier),
-            parameters,
+/// `(try body... (catch name handler...) (finally cleanup...))`, with at
+/// least one of the clauses, in that order, at the end.
+fn try_expression(items: Vec<Datum>, line: usize) -> AstNode {
+    let mut items: Vec<Datum> = items.into_iter().skip(1).collect();
+
+    let finally = match items.last() {
+        Some(last) if is_form(last, "finally") => {
+            let DatumKind::List(clause) = items.pop().unwrap().kind else {
+                unreachable!();
+            };
+            let cleanup = body(clause.into_iter().skip(1), "finally", line);
+            Some(Box::new(block(cleanup)))
+        }
+        _ => None,
+    };
+    let catch = match items.last() {
+        Some(last) if is_form(last, "catch") => {
+            let clause_line = last.line;
+            let DatumKind::List(clause) = items.pop().unwrap().kind else {
+                unreachable!();
+            };
+            let mut clause = clause.into_iter().skip(1);
+            let Some(name) = clause.next() else {
+                panic!("catch needs a name on line {}", clause_line);
+            };
+            let AstNode::Identifier { name } = identifier(name) else {
+                unreachable!();
+            };
+            let handler = body(clause, "catch", clause_line);
+            Some((name, Box::new(block(handler))))
         }
+        _ => None,
+    };
+
+    if catch.is_none() && finally.is_none() {
+        panic!("try needs a catch or finally clause on line {}", line);
+    }
+    if items.is_empty() {
+        panic!("try needs a body on line {}", line);
     }
 
-    fn function_declaration(&mut self) -> AstNode {
-        let identifier = self.expression();
-        let mut parameters = vec![];
+    AstNode::TryExpression {
+        body: Box::new(block(items)),
+        catch,
+        finally,
+    }
+}
 
-        loop {
-            if self.tokenizer.lookahead().kind == TokenKind::CloseParen {
-                break;
+/// `(import "path")` names the module after the file, `(import "path" as
+/// name)` chooses the name.
+fn import(items: Vec<Datum>, line: usize) -> AstNode {
+    let mut items = items.into_iter().skip(1);
+
+    let path = match items.next().map(|item| item.kind) {
+        Some(DatumKind::String(path)) => path,
+        _ => panic!("import needs a path on line {}", line),
+    };
+    let name = match (items.next(), items.next(), items.next()) {
+        (None, None, None) => Path::new(&path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_else(|| panic!("Can't name module {} on line {}", path, line))
+            .to_string(),
+        (Some(keyword), Some(name), None) if keyword.as_symbol() == Some("as") => {
+            match identifier(name) {
+                AstNode::Identifier { name } => name,
+                _ => unreachable!(),
             }
-            parameters.push(self.expression());
         }
+        _ => panic!("Expected (import \"path\" as name) on line {}", line),
+    };
 
-        self.check_for_close_paren();
+    AstNode::Import { path, name, line }
+}
 
-        if parameters.iter().any(|param| match param {
-            AstNode::Identifier { .. } => false,
-            _ => true,
-        }) {
-            panic!("Invalid parameters");
-        }
+/// `(match value (pattern body...)...)`. A clause with a guard is written
+/// `(pattern when test body...)`.
+fn match_expression(items: Vec<Datum>, line: usize) -> AstNode {
+    let mut items = items.into_iter().skip(1);
+    let Some(value) = items.next() else {
+        panic!("match needs a value on line {}", line);
+    };
+
+    let clauses: Vec<MatchClause> = items
+        .map(|clause| {
+            let clause_line = clause.line;
+            let DatumKind::List(items) = clause.kind else {
+                panic!("Expected a match clause on line {}", clause_line);
+            };
+            let mut items = items.into_iter();
+            let Some(pattern) = items.next() else {
+                panic!("Expected a match clause on line {}", clause_line);
+            };
+
+            let mut items = items.peekable();
+            let guard = match items.next_if(|item| item.as_symbol() == Some("when")) {
+                Some(_) => match items.next() {
+                    Some(test) => Some(expression(test)),
+                    None => panic!("when needs a test on line {}", clause_line),
+                },
+                None => None,
+            };
+
+            MatchClause {
+                pattern: self::pattern(pattern, &mut vec![]),
+                guard,
+                body: block(body(items, "match clause", clause_line)),
+            }
+        })
+        .collect();
+    if clauses.is_empty() {
+        panic!("match needs a clause on line {}", line);
+    }
 
-        let body = self.expression();
+    AstNode::MatchExpression {
+        value: Box::new(expression(value)),
+        clauses,
+    }
+}
 
-        match body {
-            AstNode::Block { .. } => {}
-            _ => panic!("Invalid body"),
+/// `names` collects the names bound so far, each may only be bound once.
+fn pattern(datum: Datum, names: &mut Vec<String>) -> Pattern {
+    let line = datum.line;
+    if is_form(&datum, "quote") {
+        let DatumKind::List(items) = datum.kind else {
+            unreachable!();
         };
+        let [quoted] = arguments(items, line);
+        return quoted_pattern(&quoted);
+    }
 
-        self.check_for_close_paren();
+    match datum.kind {
+        DatumKind::Symbol(name) if name == "_" => Pattern::Wildcard,
+        DatumKind::Symbol(name) if name == "." => panic!("Unexpected . on line {}", line),
+        DatumKind::Symbol(name) => {
+            if names.contains(&name) {
+                panic!("{} is bound twice in a pattern on line {}", name, line);
+            }
+            names.push(name.clone());
+            Pattern::Variable(name)
+        }
+        DatumKind::List(mut items) => {
+            let rest = match items.iter().rposition(|item| item.as_symbol() == Some(".")) {
+                Some(index) if index + 2 == items.len() => {
+                    let rest = items.pop().unwrap();
+                    items.pop();
+                    Some(Box::new(pattern(rest, names)))
+                }
+                Some(_) => panic!("Expected one pattern after . on line {}", line),
+                None => None,
+            };
 
-        AstNode::FunctionDeclaration {
-            identifier: Box::new(identifier),
-            parameters,
-            body: Box::new(body),
+            Pattern::List {
+                elements: items.into_iter().map(|item| pattern(item, names)).collect(),
+                rest,
+            }
         }
+        _ => Pattern::Literal(Value::from(&datum)),
     }
+}
 
-    fn while_expression(&mut self) -> AstNode {
-        let result = AstNode::WhileExpression {
-            condition: Box::new(self.expression()),
-            body: Box::new(self.expression()),
-        };
+/// A quoted datum matches equal data.
+fn quoted_pattern(datum: &Datum) -> Pattern {
+    match &datum.kind {
+        DatumKind::List(items) => Pattern::List {
+            elements: items.iter().map(quoted_pattern).collect(),
+            rest: None,
+        },
+        _ => Pattern::Literal(Value::from(datum)),
+    }
+}
 
-        self.check_for_close_paren();
+fn body(data: impl Iterator<Item = Datum>, name: &str, line: usize) -> Vec<Datum> {
+    let body: Vec<Datum> = data.collect();
+    if body.is_empty() {
+        panic!("{} needs a body on line {}", name, line);
+    }
+    body
+}
 
-        result
+fn declaration(identifier: AstNode, value: AstNode) -> AstNode {
+    AstNode::VariableDeclaration {
+        identifier: Box::new(identifier),
+        value: Box::new(value),
     }
+}
 
-    fn block(&mut self) -> AstNode {
-        AstNode::Block {
-            children: self.expressions(&TokenKind::CloseParen),
-        }
+fn binary(r#type: BinaryExpressionType, left: AstNode, right: AstNode) -> AstNode {
+    AstNode::BinaryExpression {
+        r#type,
+        left: Box::new(left),
+        right: Box::new(right),
     }
+}
+
+/// A fresh name for a variable the compiler or a derived form introduces.
+/// It contains a space, so source code can't refer to it.
+pub fn hidden_name() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(1);
+    format!(
+        "{}{}",
+        HIDDEN_PREFIX,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
 
-    fn set_variable(&mut self, r#type: SetVariableType) -> AstNode {
-        let identifier = self.expression();
-        let value = self.expression();
+pub fn is_hidden_name(name: &str) -> bool {
+    name.starts_with(HIDDEN_PREFIX)
+}
 
-        if let AstNode::Identifier { .. } = identifier {
-            self.check_for_close_paren();
+const HIDDEN_PREFIX: &str = "hidden ";
 
-            match r#type {
-                SetVariableType::Declare => AstNode::VariableDeclaration {
-                    identifier: Box::new(identifier),
-                    value: Box::new(value),
-                },
-                SetVariableType::Set => AstNode::SetVariable {
-                    identifier: Box::new(identifier),
-                    value: Box::new(value),
-                },
+fn variable(name: &str) -> AstNode {
+    AstNode::Identifier {
+        name: name.to_string(),
+    }
+}
+
+fn number(value: f64) -> AstNode {
+    AstNode::Literal {
+        r#type: LiteralType::Number,
+        value: value.to_string(),
+    }
+}
+
+/// Expands a quasiquoted datum into list building expressions. `depth`
+/// counts the enclosing quasiquotes; only unquotes at depth 1 are evaluated,
+/// deeper ones stay in the result as data.
+fn quasiquote(datum: Datum, depth: usize) -> AstNode {
+    if !has_unquote(&datum, depth) {
+        return AstNode::Quote { datum };
+    }
+
+    let line = datum.line;
+    let DatumKind::List(items) = datum.kind else {
+        unreachable!("only lists contain unquotes");
+    };
+
+    let head = items[0].clone();
+    match head.as_symbol() {
+        Some("unquote") if depth == 1 => {
+            let [value] = arguments(items, line);
+            return expression(value);
+        }
+        Some("unquote-splicing") if depth == 1 => {
+            panic!("unquote-splicing outside of a list on line {}", line)
+        }
+        Some(name @ ("quasiquote" | "unquote" | "unquote-splicing")) => {
+            let depth = if name == "quasiquote" {
+                depth + 1
+            } else {
+                depth - 1
+            };
+            let [value] = arguments(items, line);
+            return AstNode::ListExpression {
+                elements: vec![AstNode::Quote { datum: head }, quasiquote(value, depth)],
+            };
+        }
+        _ => {}
+    }
+
+    // Runs of plain elements become lists that are concatenated with the
+    // spliced ones.
+    let mut lists = vec![];
+    let mut elements = vec![];
+    for item in items {
+        if depth == 1 && is_form(&item, "unquote-splicing") {
+            let DatumKind::List(splice) = item.kind else {
+                unreachable!();
+            };
+            let [value] = arguments(splice, item.line);
+            if !elements.is_empty() {
+                lists.push(AstNode::ListExpression {
+                    elements: mem::take(&mut elements),
+                });
             }
+            lists.push(expression(value));
         } else {
-            panic!("Invalid identifier");
+            elements.push(quasiquote(item, depth));
         }
     }
 
-    fn identifier(&mut self, name: String) -> AstNode {
-        AstNode::Identifier { name }
+    if lists.is_empty() {
+        return AstNode::ListExpression { elements };
+    }
+    if !elements.is_empty() {
+        lists.push(AstNode::ListExpression { elements });
     }
+    AstNode::ConcatExpression { lists }
+}
 
-    fn if_expression(&mut self) -> AstNode {
-        let result = AstNode::IfExpression {
-            condition: Box::new(self.expression()),
-            consequent: Box::new(self.expression()),
-            alternate: Box::new(self.expression()),
-        };
+/// Whether a quasiquoted datum has anything to evaluate at `depth`.
+fn has_unquote(datum: &Datum, depth: usize) -> bool {
+    let DatumKind::List(items) = &datum.kind else {
+        return false;
+    };
+
+    let depth = match items.first().and_then(Datum::as_symbol) {
+        Some("unquote" | "unquote-splicing") if depth == 1 => return true,
+        Some("unquote" | "unquote-splicing") => depth - 1,
+        Some("quasiquote") => depth + 1,
+        _ => depth,
+    };
+    items.iter().any(|item| has_unquote(item, depth))
+}
 
-        self.check_for_close_paren();
+fn call_expression(mut items: Vec<Datum>) -> AstNode {
+    let function = items.remove(0);
 
-        result
+    AstNode::CallExpression {
+        identifier: Box::new(expression(function)),
+        parameters: items.into_iter().map(expression).collect(),
     }
+}
 
-    fn binary_expression(&mut self, r#type: BinaryExpressionType) -> AstNode {
-        let left = self.expression();
-        let right = self.expression();
-
-        self.check_for_close_paren();
+fn block(data: Vec<Datum>) -> AstNode {
+    let lines = data.iter().map(|datum| datum.line).collect();
+    let children = data.into_iter().map(expression).collect();
 
-        AstNode::BinaryExpression {
-            r#type,
-            left: Box::new(left),
-            right: Box::new(right),
-        }
-    }
+    AstNode::Block { children, lines }
+}
 
-    fn literal(&mut self, r#type: LiteralType, value: String) -> AstNode {
-        AstNode::Literal { r#type, value }
+fn identifier(datum: Datum) -> AstNode {
+    match datum.kind {
+        DatumKind::Symbol(name) => AstNode::Identifier { name },
+        _ => panic!("Expected a name on line {}", datum.line),
     }
+}
 
-    fn check_for_close_paren(&mut self) {
-        if self.tokenizer.get_next_token().kind != TokenKind::CloseParen {
-            panic!("Invalid token");
-        }
-    }
+/// The operands of a special form that takes exactly `N` of them.
+fn arguments<const N: usize>(items: Vec<Datum>, line: usize) -> [Datum; N] {
+    let name = items[0].as_symbol().unwrap_or_default().to_string();
+
+    items
+        .into_iter()
+        .skip(1)
+        .collect::<Vec<Datum>>()
+        .try_into()
+        .unwrap_or_else(|_| {
+            panic!(
+                "{} expects {} argument{} on line {}",
+                name,
+                N,
+                if N == 1 { "" } else { "s" },
+                line
+            )
+        })
+}
 
-    fn gen_next_token(&mut self) {
-        self.prev_token = self.cur_token.clone();
-        self.cur_token = Some(self.tokenizer.get_next_token());
+fn is_form(datum: &Datum, name: &str) -> bool {
+    match &datum.kind {
+        DatumKind::List(items) => items.first().and_then(Datum::as_symbol) == Some(name),
+        _ => false,
     }
 }
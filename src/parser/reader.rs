@@ -0,0 +1,202 @@
+use std::fmt;
+
+use super::tokenizer::{TokenKind, Tokenizer};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DatumKind {
+    Number(f64),
+    String(String),
+    Boolean(bool),
+    Symbol(String),
+    List(Vec<Datum>),
+}
+
+/// A piece of source read without giving it any meaning, so that `if` and
+/// `var` are plain symbols at this stage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Datum {
+    pub kind: DatumKind,
+    // Source line the datum starts on.
+    pub line: usize,
+}
+
+impl Datum {
+    pub fn as_symbol(&self) -> Option<&str> {
+        match &self.kind {
+            DatumKind::Symbol(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Prints the datum so that the reader reads it back, with `(quote x)` and
+/// its relatives in their short forms.
+impl fmt::Display for Datum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            DatumKind::Number(val) => write!(f, "{}", val),
+            DatumKind::String(val) => write!(f, "{:?}", val),
+            DatumKind::Boolean(val) => write!(f, "{}", val),
+            DatumKind::Symbol(name) => write!(f, "{}", name),
+            DatumKind::List(items) => {
+                let prefix = match items.first().and_then(Datum::as_symbol) {
+                    _ if items.len() != 2 => None,
+                    Some("quote") => Some("'"),
+                    Some("quasiquote") => Some("`"),
+                    Some("unquote") => Some(","),
+                    Some("unquote-splicing") => Some(",@"),
+                    _ => None,
+                };
+                if let Some(prefix) = prefix {
+                    return write!(f, "{}{}", prefix, items[1]);
+                }
+
+                write!(f, "(")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+pub struct Reader {
+    tokenizer: Tokenizer,
+}
+
+impl Reader {
+    pub fn new(source_code: String) -> Reader {
+        Reader {
+            tokenizer: Tokenizer::new(source_code),
+        }
+    }
+
+    /// Reads every datum up to the end of the input.
+    pub fn read_all(&mut self) -> Vec<Datum> {
+        let mut data = vec![];
+
+        while self.peek().unwrap_or_else(|message| panic!("{}", message)) != TokenKind::EndOfFile {
+            data.push(self.read());
+        }
+
+        data
+    }
+
+    pub fn read(&mut self) -> Datum {
+        self.try_read()
+            .unwrap_or_else(|message| panic!("{}", message))
+    }
+
+    /// Like `read`, but returns malformed input as an error instead of
+    /// panicking, for callers that read data rather than a program.
+    pub fn try_read(&mut self) -> Result<Datum, String> {
+        self.skip_datum_comments()?;
+
+        let token = self.tokenizer.try_get_next_token()?;
+        let kind = match token.kind {
+            TokenKind::OpenParen => {
+                let mut items = vec![];
+                loop {
+                    match self.peek()? {
+                        TokenKind::CloseParen => {
+                            self.tokenizer.try_get_next_token()?;
+                            break;
+                        }
+                        TokenKind::EndOfFile => {
+                            return Err(format!(
+                                "Missing ) for the list opened on line {}",
+                                token.line
+                            ));
+                        }
+                        _ => items.push(self.try_read()?),
+                    }
+                }
+                DatumKind::List(items)
+            }
+            TokenKind::CloseParen => return Err(format!("Unexpected ) on line {}", token.line)),
+            TokenKind::NumberLiteral => DatumKind::Number(token.value.parse().unwrap()),
+            TokenKind::StringLiteral => DatumKind::String(unescape(&token.value)),
+            TokenKind::BooleanLiteral => DatumKind::Boolean(token.value == "true"),
+            TokenKind::Symbol => DatumKind::Symbol(token.value),
+            TokenKind::Quote => self.prefixed("quote", token.line)?,
+            TokenKind::Quasiquote => self.prefixed("quasiquote", token.line)?,
+            TokenKind::Unquote => self.prefixed("unquote", token.line)?,
+            TokenKind::UnquoteSplicing => self.prefixed("unquote-splicing", token.line)?,
+            TokenKind::DatumComment | TokenKind::EndOfFile => {
+                return Err(format!("Expected an expression on line {}", token.line));
+            }
+        };
+
+        Ok(Datum {
+            kind,
+            line: token.line,
+        })
+    }
+
+    /// `'x` and the other prefixes read as `(quote x)` and so on.
+    fn prefixed(&mut self, name: &str, line: usize) -> Result<DatumKind, String> {
+        let symbol = Datum {
+            kind: DatumKind::Symbol(name.to_string()),
+            line,
+        };
+        Ok(DatumKind::List(vec![symbol, self.try_read()?]))
+    }
+
+    /// Kind of the next token after any `#;` datum comments.
+    fn peek(&mut self) -> Result<TokenKind, String> {
+        self.skip_datum_comments()?;
+        self.tokenizer.try_peek_kind()
+    }
+
+    fn skip_datum_comments(&mut self) -> Result<(), String> {
+        while self.tokenizer.try_peek_kind()? == TokenKind::DatumComment {
+            self.tokenizer.try_get_next_token()?;
+            self.try_read()?;
+        }
+        Ok(())
+    }
+}
+
+/// Contents of a string literal with its quotes removed and escapes
+/// resolved.
+fn unescape(literal: &str) -> String {
+    let mut string = String::new();
+    let mut chars = literal[1..literal.len() - 1].chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('t') => string.push('\t'),
+            Some('r') => string.push('\r'),
+            Some('0') => string.push('\0'),
+            // `{:?}` writes unprintable characters as `\u{hex}`.
+            Some('u') if chars.as_str().starts_with('{') => {
+                let rest = chars.as_str();
+                let escaped = rest.find('}').and_then(|end| {
+                    let code = u32::from_str_radix(&rest[1..end], 16).ok()?;
+                    Some((char::from_u32(code)?, end))
+                });
+                match escaped {
+                    Some((c, end)) => {
+                        string.push(c);
+                        chars = rest[end + 1..].chars();
+                    }
+                    None => string.push('u'),
+                }
+            }
+            Some(c) => string.push(c),
+            None => {}
+        }
+    }
+
+    string
+}
@@ -1,227 +1,223 @@
-use regex::Regex;
-
 #[derive(Clone, Debug, PartialEq)]
 pub enum TokenKind {
     // Parens
     OpenParen,
     CloseParen,
 
-    // Math
-    Add,
-    Sub,
-    Mul,
-    Div,
-
     // Literals
     NumberLiteral,
     StringLiteral,
     BooleanLiteral,
 
-    // Control flow
-    If,
-    While,
-
-    // Comparison
-    Greater,
-    GreaterEqual,
-    Lesser,
-    LesserEqual,
-    Equal,
-
-    // Variables
-    VariableDeclaration,
-    SetVariable,
-    Identifier,
+    // Names, including keywords and operators
+    Symbol,
 
-    // Functions
-    FunctionDeclaration,
-    CallFunction,
-
-    // Block
-    BeginBlock,
+    // Quote prefixes: ' ` , ,@
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
 
     // Special
-    Whitespace,
+    DatumComment,
     EndOfFile,
 }
 
-struct Token {
-    kind: TokenKind,
-    test: Regex,
-}
-
-const NUMBER_OF_TOKENS: usize = 23;
-
 pub struct Tokenizer {
     input: String,
+    // Byte offset of the next character to scan.
     cursor: usize,
-    tokens: [Token; NUMBER_OF_TOKENS],
+    line: usize,
 }
 
 #[derive(Debug, Clone)]
 pub struct CurrentToken {
     pub kind: TokenKind,
     pub value: String,
+    pub line: usize,
 }
 
 impl Tokenizer {
-    pub fn get_next_token(&mut self) -> CurrentToken {
-        for token in self.tokens.iter() {
-            if let Some(captures) = token.test.captures(&self.input.clone()) {
-                let result = captures.get(0).unwrap().as_str();
-
-                let length = result.len();
-                self.cursor = length;
-
-                if self.cursor >= self.input.len() {
-                    return CurrentToken {
-                        kind: TokenKind::EndOfFile,
-                        value: "".into(),
-                    };
-                }
-
-                self.input = self.input[self.cursor..].to_string();
-                if token.kind == TokenKind::Whitespace {
-                    return self.get_next_token();
-                }
-
-                return CurrentToken {
-                    kind: token.kind.clone(),
-                    value: result.to_string(),
-                };
-            }
+    pub fn new(input: String) -> Tokenizer {
+        Tokenizer {
+            input,
+            cursor: 0,
+            line: 1,
         }
+    }
+
+    pub fn get_next_token(&mut self) -> CurrentToken {
+        self.try_get_next_token()
+            .unwrap_or_else(|message| panic!("{}", message))
+    }
+
+    /// Like `get_next_token`, but returns malformed input as an error.
+    pub fn try_get_next_token(&mut self) -> Result<CurrentToken, String> {
+        self.skip_comments()?;
+
+        let line = self.line;
+        let (kind, length) = self.scan()?;
+        let value = self.input[self.cursor..self.cursor + length].to_string();
+        self.advance(length);
 
-        panic!("Invalid token");
+        Ok(CurrentToken { kind, value, line })
     }
 
     pub fn lookahead(&mut self) -> CurrentToken {
-        for token in self.tokens.iter() {
-            if let Some(captures) = token.test.captures(&self.input) {
-                let result = captures.get(0).unwrap().as_str();
-
-                if token.kind == TokenKind::Whitespace {
-                    let length = result.len();
-                    self.cursor = length;
-
-                    if self.cursor > self.input.len() {
-                        return CurrentToken {
-                            kind: TokenKind::EndOfFile,
-                            value: "".into(),
-                        };
-                    }
-                    self.input = self.input[self.cursor..].to_string();
-                    return self.lookahead();
-                }
+        let scanned = self.skip_comments().and_then(|_| self.scan());
+        let (kind, length) = scanned.unwrap_or_else(|message| panic!("{}", message));
+
+        CurrentToken {
+            kind,
+            value: self.input[self.cursor..self.cursor + length].to_string(),
+            line: self.line,
+        }
+    }
+
+    /// Kind of the next token, without copying its text.
+    pub fn peek_kind(&mut self) -> TokenKind {
+        self.try_peek_kind()
+            .unwrap_or_else(|message| panic!("{}", message))
+    }
 
-                return CurrentToken {
-                    kind: token.kind.clone(),
-                    value: result.to_string(),
+    pub fn try_peek_kind(&mut self) -> Result<TokenKind, String> {
+        self.skip_comments()?;
+        Ok(self.scan()?.0)
+    }
+
+    /// Kind and byte length of the longest token at the cursor.
+    fn scan(&self) -> Result<(TokenKind, usize), String> {
+        let rest = &self.input[self.cursor..];
+        let mut chars = rest.chars();
+
+        let Some(c) = chars.next() else {
+            return Ok((TokenKind::EndOfFile, 0));
+        };
+        let next = chars.next();
+
+        Ok(match c {
+            '(' => (TokenKind::OpenParen, 1),
+            ')' => (TokenKind::CloseParen, 1),
+            '\'' => (TokenKind::Quote, 1),
+            '`' => (TokenKind::Quasiquote, 1),
+            ',' if next == Some('@') => (TokenKind::UnquoteSplicing, 2),
+            ',' => (TokenKind::Unquote, 1),
+            '"' => (TokenKind::StringLiteral, string_length(rest)?),
+            '#' if next == Some(';') => (TokenKind::DatumComment, 2),
+            _ => {
+                let length = rest.find(is_delimiter).unwrap_or(rest.len());
+
+                let kind = match &rest[..length] {
+                    "true" | "false" => TokenKind::BooleanLiteral,
+                    word if is_number(word) => TokenKind::NumberLiteral,
+                    word if word.starts_with(|c: char| c.is_ascii_digit()) => {
+                        return Err(format!("Malformed number {} on line {}", word, self.line));
+                    }
+                    _ => TokenKind::Symbol,
                 };
+
+                (kind, length)
+            }
+        })
+    }
+
+    /// Skips whitespace, `;` line comments and nestable `#| ... |#` block
+    /// comments. `#;` datum comments are tokens, the reader skips the datum
+    /// that follows them.
+    fn skip_comments(&mut self) -> Result<(), String> {
+        loop {
+            let rest = &self.input[self.cursor..];
+            let whitespace = rest.len() - rest.trim_start().len();
+            self.advance(whitespace);
+
+            let rest = &self.input[self.cursor..];
+            if rest.starts_with(';') {
+                let length = rest.find('\n').unwrap_or(rest.len());
+                self.advance(length);
+            } else if rest.starts_with("#|") {
+                let length = block_comment_length(rest)?;
+                self.advance(length);
+            } else {
+                return Ok(());
             }
         }
+    }
 
-        panic!("Invalid token");
+    fn advance(&mut self, length: usize) {
+        let end = self.cursor + length;
+        self.line += self.input.as_bytes()[self.cursor..end]
+            .iter()
+            .filter(|&&byte| byte == b'\n')
+            .count();
+        self.cursor = end;
     }
+}
 
-    pub fn new(input: String) -> Tokenizer {
-        Tokenizer {
-            input,
-            cursor: 0,
-            tokens: [
-                Token {
-                    kind: TokenKind::OpenParen,
-                    test: Regex::new(r"^\(").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::CloseParen,
-                    test: Regex::new(r"^\)").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::Add,
-                    test: Regex::new(r"^\+").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::Sub,
-                    test: Regex::new(r"^-").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::Mul,
-                    test: Regex::new(r"^\*").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::Div,
-                    test: Regex::new(r"^/").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::NumberLiteral,
-                    test: Regex::new(r"^\d+\b").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::StringLiteral,
-                    test: Regex::new("^\".*\"").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::BooleanLiteral,
-                    test: Regex::new(r"^(true|false)\b").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::If,
-                    test: Regex::new(r"^if\b").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::Whitespace,
-                    test: Regex::new(r"^\s+").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::Greater,
-                    test: Regex::new(r"^>").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::GreaterEqual,
-                    test: Regex::new(r"^>=").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::Lesser,
-                    test: Regex::new("^<").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::LesserEqual,
-                    test: Regex::new("^<=").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::Equal,
-                    test: Regex::new(r"^=").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::VariableDeclaration,
-                    test: Regex::new(r"^var\b").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::SetVariable,
-                    test: Regex::new(r"^set\b").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::BeginBlock,
-                    test: Regex::new(r"^begin\b").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::While,
-                    test: Regex::new(r"^while\b").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::FunctionDeclaration,
-                    test: Regex::new(r"^def\b").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::CallFunction,
-                    test: Regex::new(r"^call\b").unwrap(),
-                },
-                Token {
-                    kind: TokenKind::Identifier,
-                    test: Regex::new(r"^\w*").unwrap(),
-                },
-            ],
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()\";'`,".contains(c)
+}
+
+/// An optional minus sign, digits with an optional point, and an optional
+/// exponent, as in `-1.5e-3`. Either side of the point may be empty, but
+/// not both.
+fn is_number(word: &str) -> bool {
+    let word = word.strip_prefix('-').unwrap_or(word);
+    let (mantissa, exponent) = match word.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (word, None),
+    };
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    let is_exponent = |exponent: &str| {
+        let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        !digits.is_empty() && is_digits(digits)
+    };
+
+    !(integer.is_empty() && fraction.is_empty())
+        && is_digits(integer)
+        && is_digits(fraction)
+        && exponent.is_none_or(is_exponent)
+}
+
+/// Length of the block comment at the start of `input`, including nested
+/// block comments.
+fn block_comment_length(input: &str) -> Result<usize, String> {
+    let mut depth = 0;
+    let mut index = 0;
+
+    while index < input.len() {
+        let rest = &input[index..];
+        if rest.starts_with("#|") {
+            depth += 1;
+            index += 2;
+        } else if rest.starts_with("|#") {
+            depth -= 1;
+            index += 2;
+            if depth == 0 {
+                return Ok(index);
+            }
+        } else {
+            index += rest.chars().next().unwrap().len_utf8();
         }
     }
+
+    Err(String::from("Unterminated block comment"))
+}
+
+/// Length of the string literal at the start of `input`, quotes included.
+fn string_length(input: &str) -> Result<usize, String> {
+    let mut chars = input.char_indices().skip(1);
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => return Ok(index + 1),
+            _ => {}
+        }
+    }
+
+    Err(String::from("Unterminated string"))
 }
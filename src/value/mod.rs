@@ -1,24 +1,113 @@
-use crate::compiler::Var;
+mod symbol;
 
-#[derive(Clone, Debug)]
+use std::{fmt, mem, sync::Arc};
+
+use crate::{
+    compiler::{LineInfo, LocalVar},
+    parser::reader::{Datum, DatumKind},
+};
+
+pub use symbol::Symbol;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
-    Number {
-        val: f64,
-    },
-    String {
-        val: String,
-    },
-    Boolean {
-        val: bool,
-    },
-    Function {
-        name: String,
-        scope_level: u8,
-        bytecode: Vec<u8>,
-        constants: Vec<Value>,
-        vars: Vec<Var>,
-        disassembler_vars: Vec<Var>,
-    },
+    Number { val: f64 },
+    String { val: String },
+    Boolean { val: bool },
+    Symbol { val: Symbol },
+    // Shared, so copying a list onto the stack doesn't copy its elements.
+    // `Arc` rather than `Rc` keeps values usable across threads, like the
+    // prelude's.
+    List { val: Arc<Vec<Value>> },
+    // Shared with the frames running it, so a call doesn't copy it.
+    Function { val: Arc<FunctionObject> },
+}
+
+/// A compiled function: its code and what the code refers to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionObject {
+    pub name: String,
+    pub scope_level: u8,
+    pub arity: u8,
+    pub bytecode: Vec<u8>,
+    pub constants: Vec<Value>,
+    pub locals: Vec<LocalVar>,
+    pub lines: Vec<LineInfo>,
+}
+
+impl Value {
+    /// Bytes the value owns outside of its stack slot, as counted against
+    /// the VM's memory limit.
+    pub fn heap_size(&self) -> usize {
+        match self {
+            Value::String { val } => val.len(),
+            Value::List { val } => val
+                .iter()
+                .map(|element| mem::size_of::<Value>() + element.heap_size())
+                .sum(),
+            _ => 0,
+        }
+    }
+
+    /// The datum this value reads back as, used for macro expansions.
+    /// Functions have none.
+    pub fn to_datum(&self, line: usize) -> Option<Datum> {
+        let kind = match self {
+            Value::Number { val } => DatumKind::Number(*val),
+            Value::String { val } => DatumKind::String(val.clone()),
+            Value::Boolean { val } => DatumKind::Boolean(*val),
+            Value::Symbol { val } => DatumKind::Symbol(val.name().to_string()),
+            Value::List { val } => DatumKind::List(
+                val.iter()
+                    .map(|element| element.to_datum(line))
+                    .collect::<Option<_>>()?,
+            ),
+            Value::Function { .. } => return None,
+        };
+
+        Some(Datum { kind, line })
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number { val } => write!(f, "{}", val),
+            Value::String { val } => write!(f, "{}", val),
+            Value::Boolean { val } => write!(f, "{}", val),
+            Value::Symbol { val } => write!(f, "{}", val),
+            Value::List { val } => {
+                write!(f, "(")?;
+                for (index, element) in val.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " ")?;
+                    }
+                    // Strings inside lists keep their quotes so that the
+                    // list reads back as the same datum.
+                    match element {
+                        Value::String { val } => write!(f, "{:?}", val)?,
+                        _ => write!(f, "{}", element)?,
+                    }
+                }
+                write!(f, ")")
+            }
+            Value::Function { val } => write!(f, "(function) {}", val.name),
+        }
+    }
+}
+
+/// The value a quoted datum evaluates to: names become symbols and lists
+/// become list values.
+impl From<&Datum> for Value {
+    fn from(datum: &Datum) -> Value {
+        match &datum.kind {
+            DatumKind::Number(val) => number(*val),
+            DatumKind::String(val) => string(val.clone()),
+            DatumKind::Boolean(val) => boolean(*val),
+            DatumKind::Symbol(name) => symbol(name),
+            DatumKind::List(items) => list(items.iter().map(Value::from).collect()),
+        }
+    }
 }
 
 pub fn number(val: f64) -> Value {
@@ -32,3 +121,17 @@ pub fn string(val: String) -> Value {
 pub fn boolean(val: bool) -> Value {
     Value::Boolean { val }
 }
+
+pub fn function(val: FunctionObject) -> Value {
+    Value::Function { val: Arc::new(val) }
+}
+
+pub fn symbol(name: &str) -> Value {
+    Value::Symbol {
+        val: Symbol::intern(name),
+    }
+}
+
+pub fn list(val: Vec<Value>) -> Value {
+    Value::List { val: Arc::new(val) }
+}
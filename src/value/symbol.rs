@@ -0,0 +1,73 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+/// An interned name. Two symbols are equal exactly when their names are,
+/// so comparing them is an integer comparison.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+// Ids with this bit set are uninterned symbols, which have no entry in the
+// interner.
+const UNINTERNED: u32 = 1 << 31;
+
+// Numbers uninterned symbols, which print as `#:g` and the number.
+static COUNTER: AtomicU32 = AtomicU32::new(1);
+
+#[derive(Default)]
+struct Interner {
+    names: Vec<&'static str>,
+    ids: HashMap<&'static str, u32>,
+}
+
+fn interner() -> &'static Mutex<Interner> {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(Mutex::default)
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        let mut interner = interner().lock().unwrap();
+        if let Some(&id) = interner.ids.get(name) {
+            return Symbol(id);
+        }
+
+        // Names live as long as the program, like the symbols themselves.
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        let id = interner.names.len() as u32;
+        interner.names.push(name);
+        interner.ids.insert(name, id);
+        Symbol(id)
+    }
+
+    /// A fresh symbol equal only to itself, for programs that make symbols
+    /// at run time. It takes no space in the interner.
+    pub fn uninterned() -> Symbol {
+        Symbol(UNINTERNED | COUNTER.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn name(self) -> Cow<'static, str> {
+        if self.0 & UNINTERNED != 0 {
+            return Cow::Owned(format!("#:g{}", self.0 & !UNINTERNED));
+        }
+        Cow::Borrowed(interner().lock().unwrap().names[self.0 as usize])
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}", self.name())
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
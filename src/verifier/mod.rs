@@ -0,0 +1,331 @@
+use std::fmt;
+
+use crate::{compiler::CompileResult, value::Value, vm::*};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyErrorKind {
+    UnknownInstruction(u8),
+    TruncatedInstruction,
+    ConstantOutOfBounds { index: u8, len: usize },
+    UnknownNative(u8),
+    VarOutOfBounds { slot: u8, depth: usize },
+    JumpOutOfBounds { target: usize },
+    JumpIntoInstruction { target: usize },
+    StackUnderflow { needed: usize, depth: usize },
+    InconsistentStackDepth { expected: usize, found: usize },
+    InconsistentHandlers { expected: usize, found: usize },
+    HandlerUnderflow,
+    OpenHandlers { count: usize },
+    InvalidTerminator,
+    MissingTerminator,
+}
+
+/// Where and why a program was rejected. `address` is the offset of the
+/// offending instruction inside the bytecode of `function`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub function: String,
+    pub address: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {:04x}: ", self.function, self.address)?;
+
+        match &self.kind {
+            VerifyErrorKind::UnknownInstruction(op_code) => {
+                write!(f, "unknown instruction {:02x}", op_code)
+            }
+            VerifyErrorKind::TruncatedInstruction => {
+                write!(f, "instruction is missing its operands")
+            }
+            VerifyErrorKind::ConstantOutOfBounds { index, len } => write!(
+                f,
+                "constant {} out of bounds (constant pool has {} entries)",
+                index, len
+            ),
+            VerifyErrorKind::UnknownNative(index) => write!(f, "unknown native {}", index),
+            VerifyErrorKind::VarOutOfBounds { slot, depth } => write!(
+                f,
+                "variable slot {} out of bounds (frame has {} slots)",
+                slot, depth
+            ),
+            VerifyErrorKind::JumpOutOfBounds { target } => {
+                write!(f, "jump target {:04x} is past the end", target)
+            }
+            VerifyErrorKind::JumpIntoInstruction { target } => write!(
+                f,
+                "jump target {:04x} is not an instruction boundary",
+                target
+            ),
+            VerifyErrorKind::StackUnderflow { needed, depth } => write!(
+                f,
+                "needs {} values on the stack but only {} are there",
+                needed, depth
+            ),
+            VerifyErrorKind::InconsistentStackDepth { expected, found } => write!(
+                f,
+                "stack depth {} differs from {} on another path",
+                found, expected
+            ),
+            VerifyErrorKind::InconsistentHandlers { expected, found } => write!(
+                f,
+                "{} exception handlers are installed but {} on another path",
+                found, expected
+            ),
+            VerifyErrorKind::HandlerUnderflow => {
+                write!(f, "END_TRY without an installed exception handler")
+            }
+            VerifyErrorKind::OpenHandlers { count } => {
+                write!(f, "{} exception handlers are still installed", count)
+            }
+            VerifyErrorKind::InvalidTerminator => {
+                write!(f, "HALT and RETURN must not be mixed")
+            }
+            VerifyErrorKind::MissingTerminator => {
+                write!(f, "execution runs past the end of the bytecode")
+            }
+        }
+    }
+}
+
+/// Checks that `result` can be executed without the VM indexing out of
+/// bounds: every op code is known, operands refer to existing constants,
+/// variable slots and instruction boundaries, and each instruction is
+/// reached with the same stack depth and exception handlers on every path.
+pub fn verify(result: &CompileResult) -> Result<(), VerifyError> {
+    verify_function("main", &result.bytecode, &result.constants, 0, OP_HALT)
+}
+
+fn verify_function(
+    name: &str,
+    bytecode: &[u8],
+    constants: &[Value],
+    initial_depth: usize,
+    terminator: u8,
+) -> Result<(), VerifyError> {
+    let error = |address: usize, kind: VerifyErrorKind| VerifyError {
+        function: name.to_string(),
+        address,
+        kind,
+    };
+
+    // First pass: decode linearly to find instruction boundaries and check
+    // everything that does not depend on the stack.
+    let mut is_boundary = vec![false; bytecode.len() + 1];
+    let mut ip = 0;
+    while ip < bytecode.len() {
+        let op_code = bytecode[ip];
+        let length = operand_count(op_code)
+            .ok_or_else(|| error(ip, VerifyErrorKind::UnknownInstruction(op_code)))?;
+
+        if ip + length >= bytecode.len() {
+            return Err(error(ip, VerifyErrorKind::TruncatedInstruction));
+        }
+
+        is_boundary[ip] = true;
+
+        match op_code {
+            OP_CONST | OP_INC_VAR => {
+                let index = bytecode[ip + length];
+                if index as usize >= constants.len() {
+                    return Err(error(
+                        ip,
+                        VerifyErrorKind::ConstantOutOfBounds {
+                            index,
+                            len: constants.len(),
+                        },
+                    ));
+                }
+            }
+            OP_NATIVE => {
+                let index = bytecode[ip + 1];
+                if index as usize >= NATIVES.len() {
+                    return Err(error(ip, VerifyErrorKind::UnknownNative(index)));
+                }
+            }
+            OP_HALT | OP_RETURN if op_code != terminator => {
+                return Err(error(ip, VerifyErrorKind::InvalidTerminator));
+            }
+            _ => {}
+        }
+
+        ip += 1 + length;
+    }
+
+    for (index, jump) in jumps(bytecode) {
+        if jump > bytecode.len() {
+            return Err(error(
+                index,
+                VerifyErrorKind::JumpOutOfBounds { target: jump },
+            ));
+        }
+        if !is_boundary[jump] {
+            return Err(error(
+                index,
+                VerifyErrorKind::JumpIntoInstruction { target: jump },
+            ));
+        }
+    }
+
+    // Second pass: follow every path and track the stack depth relative to
+    // the frame's base pointer, along with the number of handlers the
+    // function installed with TRY.
+    let mut depths: Vec<Option<(usize, usize)>> = vec![None; bytecode.len() + 1];
+    let mut pending = vec![(0, initial_depth, 0)];
+
+    while let Some((ip, depth, handlers)) = pending.pop() {
+        if ip == bytecode.len() {
+            return Err(error(ip, VerifyErrorKind::MissingTerminator));
+        }
+
+        match depths[ip] {
+            Some((expected, _)) if expected != depth => {
+                return Err(error(
+                    ip,
+                    VerifyErrorKind::InconsistentStackDepth {
+                        expected,
+                        found: depth,
+                    },
+                ));
+            }
+            Some((_, expected)) if expected != handlers => {
+                return Err(error(
+                    ip,
+                    VerifyErrorKind::InconsistentHandlers {
+                        expected,
+                        found: handlers,
+                    },
+                ));
+            }
+            Some(_) => continue,
+            None => depths[ip] = Some((depth, handlers)),
+        }
+
+        let op_code = bytecode[ip];
+        let operand = bytecode.get(ip + 1).copied().unwrap_or(0);
+        let target = if is_jump(op_code) {
+            decode_address(&bytecode[ip + 1..])
+        } else {
+            0
+        };
+        let next = ip + 1 + operand_count(op_code).unwrap();
+
+        let require = |needed: usize| {
+            if depth < needed {
+                Err(error(ip, VerifyErrorKind::StackUnderflow { needed, depth }))
+            } else {
+                Ok(())
+            }
+        };
+        let require_slot = |slot: u8| {
+            if slot as usize >= depth {
+                Err(error(ip, VerifyErrorKind::VarOutOfBounds { slot, depth }))
+            } else {
+                Ok(())
+            }
+        };
+
+        match op_code {
+            OP_HALT | OP_RETURN => {
+                require(1)?;
+                if handlers > 0 {
+                    return Err(error(ip, VerifyErrorKind::OpenHandlers { count: handlers }));
+                }
+            }
+            OP_CONST | OP_GENSYM => pending.push((next, depth + 1, handlers)),
+            OP_ADD | OP_SUB | OP_MUL | OP_DIV | OP_GT | OP_GTE | OP_LT | OP_LTE | OP_EQ
+            | OP_NTH | OP_DROP => {
+                require(2)?;
+                pending.push((next, depth - 1, handlers));
+            }
+            OP_JUMP_IF_FALSE => {
+                require(1)?;
+                pending.push((next, depth - 1, handlers));
+                pending.push((target, depth - 1, handlers));
+            }
+            OP_JUMP => pending.push((target, depth, handlers)),
+            // The handler starts with the thrown value on the stack, and
+            // is removed before it runs.
+            OP_TRY => {
+                pending.push((next, depth, handlers + 1));
+                pending.push((target, depth + 1, handlers));
+            }
+            OP_END_TRY => {
+                if handlers == 0 {
+                    return Err(error(ip, VerifyErrorKind::HandlerUnderflow));
+                }
+                pending.push((next, depth, handlers - 1));
+            }
+            OP_THROW => require(1)?,
+            OP_SET_VAR => {
+                require(1)?;
+                require_slot(operand)?;
+                pending.push((next, depth, handlers));
+            }
+            OP_GET_VAR | OP_INC_VAR => {
+                require_slot(operand)?;
+                pending.push((next, depth + 1, handlers));
+            }
+            OP_LENGTH | OP_TYPE_OF => {
+                require(1)?;
+                pending.push((next, depth, handlers));
+            }
+            OP_POP => {
+                require(1)?;
+                pending.push((next, depth - 1, handlers));
+            }
+            OP_SCOPE_EXIT => {
+                require(operand as usize + 1)?;
+                pending.push((next, depth - operand as usize, handlers));
+            }
+            OP_CALL => {
+                require(operand as usize + 1)?;
+                pending.push((next, depth - operand as usize, handlers));
+            }
+            OP_NATIVE => {
+                let arity = NATIVES[operand as usize].arity;
+                require(arity)?;
+                pending.push((next, depth - arity + 1, handlers));
+            }
+            OP_LIST | OP_CONCAT => {
+                require(operand as usize)?;
+                pending.push((next, depth - operand as usize + 1, handlers));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    for constant in constants {
+        if let Value::Function { val: function } = constant {
+            // Slot 0 holds the function itself, followed by its parameters.
+            verify_function(
+                &function.name,
+                &function.bytecode,
+                &function.constants,
+                1 + function.arity as usize,
+                OP_RETURN,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Addresses of the jump instructions in `bytecode` and where they lead.
+/// Must only be called on bytecode that decodes cleanly.
+fn jumps(bytecode: &[u8]) -> Vec<(usize, usize)> {
+    let mut jumps = vec![];
+
+    let mut ip = 0;
+    while ip < bytecode.len() {
+        let op_code = bytecode[ip];
+        if is_jump(op_code) {
+            jumps.push((ip, decode_address(&bytecode[ip + 1..])));
+        }
+        ip += 1 + operand_count(op_code).unwrap();
+    }
+
+    jumps
+}
@@ -1,4 +1,24 @@
-use crate::value::{boolean, number, string, Value};
+use std::{
+    collections::HashMap,
+    fmt, mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use crate::value::{boolean, list, number, string, symbol, FunctionObject, Symbol, Value};
+
+pub use self::{
+    natives::{native_index, Native, Random, NATIVES},
+    profiler::{FunctionProfile, Profiler},
+    trace::Trace,
+};
+
+mod natives;
+mod profiler;
+mod trace;
 
 pub const OP_HALT: u8 = 0x00;
 pub const OP_CONST: u8 = 0x01;
@@ -19,13 +39,24 @@ pub const OP_POP: u8 = 0x0f;
 pub const OP_SCOPE_EXIT: u8 = 0x10;
 pub const OP_CALL: u8 = 0x11;
 pub const OP_RETURN: u8 = 0x12;
-pub const OP_PARAM: u8 = 0x13;
+pub const OP_INC_VAR: u8 = 0x13;
+pub const OP_LIST: u8 = 0x14;
+pub const OP_CONCAT: u8 = 0x15;
+pub const OP_GENSYM: u8 = 0x16;
+pub const OP_LENGTH: u8 = 0x17;
+pub const OP_NTH: u8 = 0x18;
+pub const OP_TRY: u8 = 0x19;
+pub const OP_END_TRY: u8 = 0x1a;
+pub const OP_THROW: u8 = 0x1b;
+pub const OP_TYPE_OF: u8 = 0x1c;
+pub const OP_DROP: u8 = 0x1d;
+pub const OP_NATIVE: u8 = 0x1e;
 
 enum MathOperation {
-    ADD,
-    SUB,
-    MUL,
-    DIV,
+    Add,
+    Sub,
+    Mul,
+    Div,
 }
 
 enum ComparisonOperation {
@@ -36,178 +67,680 @@ enum ComparisonOperation {
     Equal,
 }
 
-const STACK_SIZE: usize = 20;
+const DEFAULT_MAX_STACK_SIZE: usize = 4096;
+const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+// Reading the clock on every instruction would dominate the dispatch loop,
+// so the deadline and cancellation flag are only polled this often.
+const LIMIT_CHECK_INTERVAL: u64 = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    OutOfFuel,
+    DeadlineExceeded,
+    Cancelled,
+    StackOverflow,
+    OutOfMemory,
+    // Only bytecode that skipped `verifier::verify` can contain one.
+    InvalidInstruction(u8),
+    // A thrown value no handler caught. Errors of the program itself, like
+    // invalid operands, are thrown as `(kind "message")` lists.
+    Exception(Box<Value>),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::OutOfFuel => write!(f, "instruction budget exhausted"),
+            RuntimeError::DeadlineExceeded => write!(f, "deadline exceeded"),
+            RuntimeError::Cancelled => write!(f, "execution cancelled"),
+            RuntimeError::StackOverflow => write!(f, "stack overflow"),
+            RuntimeError::OutOfMemory => write!(f, "memory limit exceeded"),
+            RuntimeError::InvalidInstruction(op_code) => {
+                write!(f, "invalid instruction {:02x}", op_code)
+            }
+            RuntimeError::Exception(value) => write!(f, "uncaught exception: {}", value),
+        }
+    }
+}
+
+/// Stops a running `VM::exec` from another thread. Clones share the same
+/// flag, which stays set until `reset` is called.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Number of operand bytes following each op code, or `None` for bytes
+/// that are not a known instruction.
+pub fn operand_count(op_code: u8) -> Option<usize> {
+    match op_code {
+        OP_HALT | OP_ADD | OP_SUB | OP_MUL | OP_DIV | OP_GT | OP_GTE | OP_LT | OP_LTE | OP_EQ
+        | OP_POP | OP_RETURN | OP_GENSYM | OP_LENGTH | OP_NTH | OP_END_TRY | OP_THROW
+        | OP_TYPE_OF | OP_DROP => Some(0),
+        OP_CONST | OP_SET_VAR | OP_GET_VAR | OP_SCOPE_EXIT | OP_CALL | OP_LIST | OP_CONCAT
+        | OP_NATIVE => Some(1),
+        OP_JUMP_IF_FALSE | OP_JUMP | OP_TRY | OP_INC_VAR => Some(2),
+        _ => None,
+    }
+}
+
+/// Jumps and handlers take an absolute address, stored in two bytes, low
+/// byte first.
+pub fn is_jump(op_code: u8) -> bool {
+    matches!(op_code, OP_JUMP | OP_JUMP_IF_FALSE | OP_TRY)
+}
+
+/// The address held by the two operand bytes at the start of `bytes`.
+pub fn decode_address(bytes: &[u8]) -> usize {
+    u16::from_le_bytes([bytes[0], bytes[1]]) as usize
+}
+
+/// The operand bytes of a jump to `address`, or `None` if it is too far.
+pub fn encode_address(address: usize) -> Option<[u8; 2]> {
+    u16::try_from(address).ok().map(u16::to_le_bytes)
+}
+
+/// Highest memory use seen during the last call to `VM::exec`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryUsage {
+    pub peak_heap_bytes: usize,
+    pub peak_stack_size: usize,
+    pub peak_call_depth: usize,
+}
+
+/// A function activation. `ip` is the address of the next instruction in
+/// the function's bytecode and `bp` the stack index of its slot 0.
+pub struct CallFrame {
+    pub ip: usize,
+    pub bp: usize,
+    function: Arc<FunctionObject>,
+}
+
+impl CallFrame {
+    pub fn name(&self) -> &str {
+        &self.function.name
+    }
+
+    pub fn bytecode(&self) -> &[u8] {
+        &self.function.bytecode
+    }
+}
+
+/// Installed by `TRY`: a throw returns to `frames` call frames and
+/// `stack_len` values, pushes the thrown value and continues at `address`
+/// in the function that installed it.
+struct Handler {
+    frames: usize,
+    stack_len: usize,
+    address: usize,
+}
 
 pub struct VM {
-    stack: [Option<Value>; STACK_SIZE],
-    sp: usize,
+    stack: Vec<Value>,
     bp: usize,
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    cancellation: CancellationHandle,
+    steps: u64,
+    max_heap_bytes: Option<usize>,
+    max_stack_size: usize,
+    max_call_depth: usize,
+    heap_bytes: usize,
+    // Lists on the stack by allocation, with the number of slots holding
+    // each and the bytes counted for it.
+    lists: HashMap<*const Vec<Value>, (usize, usize)>,
+    memory_usage: MemoryUsage,
+    trace: Option<Trace>,
+    profiler: Option<Profiler>,
+    random: Random,
 }
 
-const fn init_value() -> Option<Value> {
-    None
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-const VALUE_INITIAL: Option<Value> = init_value();
-
 impl VM {
     pub fn new() -> VM {
-        let sp = 0;
-
         VM {
-            stack: [VALUE_INITIAL; STACK_SIZE],
-            sp,
-            bp: sp,
+            stack: vec![],
+            bp: 0,
+            frames: vec![],
+            handlers: vec![],
+            fuel: None,
+            deadline: None,
+            cancellation: CancellationHandle::default(),
+            steps: 0,
+            max_heap_bytes: None,
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            heap_bytes: 0,
+            lists: HashMap::new(),
+            memory_usage: MemoryUsage::default(),
+            trace: None,
+            profiler: None,
+            random: Random::from_time(),
         }
     }
 
-    pub fn exec(&mut self, constants: Vec<Value>, bytecode: Vec<u8>) -> Value {
-        let mut ip: usize = 0;
+    /// Limits the number of instructions `exec` may run. The budget is
+    /// shared by all following calls to `exec`; `None` removes the limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Remaining instruction budget, if one is set.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    pub fn cancellation_handle(&self) -> CancellationHandle {
+        self.cancellation.clone()
+    }
+
+    /// Caps the bytes held by the strings and lists on the stack. `None`
+    /// removes the cap.
+    ///
+    /// The count is approximate. A value counts only while a stack slot
+    /// holds it, so the constant pool and values kept alive elsewhere are
+    /// left out, and a list nested in several others counts once for each.
+    pub fn set_max_heap_bytes(&mut self, max_heap_bytes: Option<usize>) {
+        self.max_heap_bytes = max_heap_bytes;
+    }
+
+    /// Caps the number of values on the stack, across all call frames.
+    pub fn set_max_stack_size(&mut self, max_stack_size: usize) {
+        self.max_stack_size = max_stack_size;
+    }
+
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.memory_usage
+    }
+
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
+    }
+
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Seeds the generator behind `random`, so runs can be reproduced. The
+    /// VM is seeded from the clock otherwise.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.random = Random::new(seed);
+    }
+
+    /// Runs `bytecode` until it halts. When a limit stops the program early
+    /// the stack and call frames are unwound, so the VM can run again.
+    pub fn exec(
+        &mut self,
+        constants: Vec<Value>,
+        bytecode: Vec<u8>,
+    ) -> Result<Value, RuntimeError> {
+        self.start(constants, bytecode);
 
         loop {
-            let instruction = bytecode[ip];
-            ip += 1;
+            if let Some(result) = self.step()? {
+                return Ok(result);
+            }
+        }
+    }
 
-            match instruction {
-                OP_HALT => {
-                    return self.stack_pop();
-                }
-                OP_CONST => {
-                    let constant = constants[bytecode[ip] as usize].clone();
-                    ip += 1;
-                    self.stack_push(constant);
-                }
-                OP_ADD => {
-                    let result = self.math_operation(MathOperation::ADD);
-                    self.stack_push(result);
-                }
-                OP_SUB => {
-                    let result = self.math_operation(MathOperation::SUB);
-                    self.stack_push(result);
-                }
-                OP_MUL => {
-                    let result = self.math_operation(MathOperation::MUL);
-                    self.stack_push(result);
-                }
-                OP_DIV => {
-                    let result = self.math_operation(MathOperation::DIV);
-                    self.stack_push(result);
-                }
-                OP_GT => {
-                    let result = self.comparison_operation(ComparisonOperation::Greater);
-                    self.stack_push(result);
-                }
-                OP_GTE => {
-                    let result = self.comparison_operation(ComparisonOperation::GreaterEqual);
-                    self.stack_push(result);
-                }
-                OP_LT => {
-                    let result = self.comparison_operation(ComparisonOperation::Lesser);
-                    self.stack_push(result);
-                }
-                OP_LTE => {
-                    let result = self.comparison_operation(ComparisonOperation::LesserEqual);
-                    self.stack_push(result);
-                }
-                OP_EQ => {
-                    let result = self.comparison_operation(ComparisonOperation::Equal);
-                    self.stack_push(result);
-                }
-                OP_JUMP_IF_FALSE => {
-                    let result = self.stack_pop();
-                    if let Value::Boolean { val } = result {
-                        if !val {
-                            ip = (bytecode[ip]) as usize;
-                        } else {
-                            ip += 1;
-                        }
-                    } else {
-                        panic!("Invalid condition expression");
+    /// Prepares `bytecode` to be run one instruction at a time with `step`.
+    pub fn start(&mut self, constants: Vec<Value>, bytecode: Vec<u8>) {
+        self.bp = self.stack.len();
+        self.frames.push(CallFrame {
+            ip: 0,
+            bp: self.bp,
+            function: Arc::new(FunctionObject {
+                name: String::from("main"),
+                scope_level: 0,
+                arity: 0,
+                bytecode,
+                constants,
+                locals: vec![],
+                lines: vec![],
+            }),
+        });
+        self.memory_usage = MemoryUsage {
+            peak_heap_bytes: self.heap_bytes,
+            peak_stack_size: self.stack.len(),
+            peak_call_depth: 1,
+        };
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.enter("main");
+        }
+    }
+
+    /// Executes the next instruction, returning the program's result once
+    /// it halts. Errors unwind the program like in `exec`.
+    pub fn step(&mut self) -> Result<Option<Value>, RuntimeError> {
+        let result = match self.execute_instruction() {
+            Err(RuntimeError::Exception(value)) if !self.handlers.is_empty() => self.throw(*value),
+            result => result,
+        };
+
+        if !matches!(result, Ok(None)) {
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.finish();
+            }
+        }
+
+        if result.is_err() {
+            let base = self.frames.first().map_or(0, |frame| frame.bp);
+            self.frames.clear();
+            self.handlers.clear();
+            self.stack_truncate(base);
+            self.bp = base;
+        }
+
+        result
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.frames.is_empty()
+    }
+
+    /// The call stack, outermost frame first.
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    pub fn sp(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn bp(&self) -> usize {
+        self.bp
+    }
+
+    fn execute_instruction(&mut self) -> Result<Option<Value>, RuntimeError> {
+        self.check_limits()?;
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.log(
+                self.frames.last().expect("No active call frame"),
+                &self.stack,
+            );
+        }
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            let frame = self.frames.last().expect("No active call frame");
+            profiler.instruction(frame.ip, frame.bytecode()[frame.ip]);
+        }
+
+        let instruction = self.read_byte();
+
+        match instruction {
+            OP_HALT => {
+                self.frames.clear();
+                self.handlers.clear();
+                return Ok(Some(self.stack_pop()));
+            }
+            OP_CONST => {
+                let position = self.read_byte();
+                let constant = self.constant(position);
+                self.stack_push(constant)?;
+            }
+            OP_ADD => {
+                let result = self.math_operation(MathOperation::Add)?;
+                self.stack_push(result)?;
+            }
+            OP_SUB => {
+                let result = self.math_operation(MathOperation::Sub)?;
+                self.stack_push(result)?;
+            }
+            OP_MUL => {
+                let result = self.math_operation(MathOperation::Mul)?;
+                self.stack_push(result)?;
+            }
+            OP_DIV => {
+                let result = self.math_operation(MathOperation::Div)?;
+                self.stack_push(result)?;
+            }
+            OP_GT => {
+                let result = self.comparison_operation(ComparisonOperation::Greater)?;
+                self.stack_push(result)?;
+            }
+            OP_GTE => {
+                let result = self.comparison_operation(ComparisonOperation::GreaterEqual)?;
+                self.stack_push(result)?;
+            }
+            OP_LT => {
+                let result = self.comparison_operation(ComparisonOperation::Lesser)?;
+                self.stack_push(result)?;
+            }
+            OP_LTE => {
+                let result = self.comparison_operation(ComparisonOperation::LesserEqual)?;
+                self.stack_push(result)?;
+            }
+            OP_EQ => {
+                let result = self.comparison_operation(ComparisonOperation::Equal)?;
+                self.stack_push(result)?;
+            }
+            OP_JUMP_IF_FALSE => {
+                let address = self.read_address();
+                let result = self.stack_pop();
+                if let Value::Boolean { val } = result {
+                    if !val {
+                        self.frame_mut().ip = address;
                     }
+                } else {
+                    return Err(error("type-error", "Invalid condition expression"));
                 }
-                OP_JUMP => {
-                    ip = (bytecode[ip]) as usize;
-                }
-                OP_GET_VAR => {
-                    let position = bytecode[ip];
-                    ip += 1;
+            }
+            OP_JUMP => {
+                let address = self.read_address();
+                self.frame_mut().ip = address;
+            }
+            OP_GET_VAR => {
+                let position = self.read_byte();
+
+                let value = self.peek(position as usize);
+                self.stack_push(value)?;
+            }
+            OP_SET_VAR => {
+                let position = self.read_byte();
+
+                let value = self.peek(self.stack.len() - self.bp - 1);
+                self.stack_set(position as usize, value)?;
+            }
+            OP_INC_VAR => {
+                let position = self.read_byte();
+                let constant_position = self.read_byte();
+                let constant = self.constant(constant_position);
+
+                let value = self.peek(position as usize);
+                self.stack_push(value)?;
+                self.stack_push(constant)?;
+
+                let result = self.math_operation(MathOperation::Add)?;
+                self.stack_set(position as usize, result.clone())?;
+                self.stack_push(result)?;
+            }
+            OP_POP => {
+                self.stack_pop();
+            }
+            OP_SCOPE_EXIT => {
+                let result = self.stack_pop();
 
-                    let value = self.peek(position as usize);
+                let number_of_vars_to_pop = self.read_byte();
+                self.stack_truncate(self.stack.len() - number_of_vars_to_pop as usize);
 
-                    self.stack_push(value.clone());
+                self.stack_push(result)?;
+            }
+            OP_CALL => {
+                let number_of_arguments = self.read_byte() as usize;
 
-                    if let Value::Function { .. } = value {
-                        self.bp = self.sp;
+                // The function object sits below its arguments and becomes
+                // slot 0 of the new frame, the arguments slots 1..=n.
+                let bp = self.stack.len() - number_of_arguments - 1;
+                if let Value::Function { val: function } = &self.stack[bp] {
+                    if function.arity as usize != number_of_arguments {
+                        return Err(error(
+                            "arity-error",
+                            &format!(
+                                "Function {} expects {} arguments, got {}",
+                                function.name, function.arity, number_of_arguments
+                            ),
+                        ));
+                    }
+
+                    if self.frames.len() >= self.max_call_depth {
+                        return Err(RuntimeError::StackOverflow);
                     }
-                }
-                OP_SET_VAR => {
-                    let position = bytecode[ip];
-                    ip += 1;
 
-                    let value = self.peek(self.sp - self.bp - 1);
-                    self.stack_set(position as usize, value.clone());
+                    let function = Arc::clone(function);
+                    self.bp = bp;
+                    self.frames.push(CallFrame {
+                        ip: 0,
+                        bp,
+                        function,
+                    });
+                    self.memory_usage.peak_call_depth =
+                        self.memory_usage.peak_call_depth.max(self.frames.len());
+
+                    if let Some(profiler) = self.profiler.as_mut() {
+                        profiler.enter(self.frames.last().unwrap().name());
+                    }
+                } else {
+                    return Err(error("type-error", "Not a function"));
                 }
-                OP_POP => {
-                    self.stack_pop();
+            }
+            OP_RETURN => {
+                let result = self.stack_pop();
+                self.stack_truncate(self.bp);
+                self.stack_push(result)?;
+
+                self.frames.pop();
+                self.bp = self.frame().bp;
+                // Handlers never outlive the function that installed them.
+                while self
+                    .handlers
+                    .last()
+                    .is_some_and(|handler| handler.frames > self.frames.len())
+                {
+                    self.handlers.pop();
                 }
-                OP_SCOPE_EXIT => {
-                    let result = self.stack_pop();
-
-                    let number_of_vars_to_pop = bytecode[ip];
-                    ip += 1;
-
-                    // There is an edge case where the block has only one variable
-                    // declaration expression. In that case,
-                    // the stack pointer will be equal to the base pointer and we
-                    // don't want to move the stack pointer.
-                    if self.sp != self.bp || number_of_vars_to_pop != 1 {
-                        self.sp -= number_of_vars_to_pop as usize;
-                    }
 
-                    self.stack_push(result);
+                if let Some(profiler) = self.profiler.as_mut() {
+                    profiler.exit();
                 }
-                OP_CALL => {
-                    if let Some(function) = self.stack[self.bp].clone() {
-                        if let Value::Function {
-                            bytecode,
-                            constants,
-                            ..
-                        } = function
-                        {
-                            self.exec(constants, bytecode);
-                        }
+            }
+            OP_LIST => {
+                let length = self.read_byte() as usize;
+                let elements = self.stack_pop_many(length);
+                self.stack_push(list(elements))?;
+            }
+            OP_CONCAT => {
+                let count = self.read_byte() as usize;
+                let mut result = vec![];
+                for value in self.stack_pop_many(count) {
+                    if let Value::List { val } = value {
+                        result.extend(val.iter().cloned());
+                    } else {
+                        return Err(invalid_operands());
                     }
                 }
-                OP_PARAM => {
-                    let position = bytecode[ip];
-                    ip += 1;
+                self.stack_push(list(result))?;
+            }
+            OP_GENSYM => {
+                self.stack_push(Value::Symbol {
+                    val: Symbol::uninterned(),
+                })?;
+            }
+            OP_LENGTH => {
+                let length = match self.stack_pop() {
+                    Value::List { val } => val.len(),
+                    _ => return Err(invalid_operands()),
+                };
+                self.stack_push(number(length as f64))?;
+            }
+            OP_NTH => {
+                let index = self.stack_pop();
+                let list = self.stack_pop();
+                let element = match (list, index) {
+                    (Value::List { val }, Value::Number { val: index })
+                        if index >= 0.0 && index.fract() == 0.0 && (index as usize) < val.len() =>
+                    {
+                        val[index as usize].clone()
+                    }
+                    (Value::List { .. }, Value::Number { val: index }) => {
+                        return Err(error(
+                            "index-error",
+                            &format!("Index {} out of bounds", index),
+                        ));
+                    }
+                    _ => return Err(invalid_operands()),
+                };
+                self.stack_push(element)?;
+            }
+            OP_TYPE_OF => {
+                let name = match self.stack_pop() {
+                    Value::Number { .. } => "number",
+                    Value::String { .. } => "string",
+                    Value::Boolean { .. } => "boolean",
+                    Value::Symbol { .. } => "symbol",
+                    Value::List { .. } => "list",
+                    Value::Function { .. } => "function",
+                };
+                self.stack_push(symbol(name))?;
+            }
+            OP_DROP => {
+                let count = self.stack_pop();
+                let value = self.stack_pop();
+                let rest = match (value, count) {
+                    (Value::List { val }, Value::Number { val: count })
+                        if count >= 0.0 && count.fract() == 0.0 && count as usize <= val.len() =>
+                    {
+                        val[count as usize..].to_vec()
+                    }
+                    (Value::List { .. }, Value::Number { val: count }) => {
+                        return Err(error(
+                            "index-error",
+                            &format!("Can't drop {} elements", count),
+                        ));
+                    }
+                    _ => return Err(invalid_operands()),
+                };
+                self.stack_push(list(rest))?;
+            }
+            OP_NATIVE => {
+                let native = &NATIVES[self.read_byte() as usize];
+                let arguments = self
+                    .stack_pop_many(native.arity)
+                    .into_iter()
+                    .map(|argument| match argument {
+                        Value::Number { val } => Ok(val),
+                        _ => Err(invalid_operands()),
+                    })
+                    .collect::<Result<Vec<f64>, _>>()?;
+                let result = native.call(&mut self.random, &arguments);
+                self.stack_push(number(result))?;
+            }
+            OP_TRY => {
+                let address = self.read_address();
+                self.handlers.push(Handler {
+                    frames: self.frames.len(),
+                    stack_len: self.stack.len(),
+                    address,
+                });
+            }
+            OP_END_TRY => {
+                self.handlers.pop();
+            }
+            OP_THROW => {
+                let value = self.stack_pop();
+                return Err(RuntimeError::Exception(Box::new(value)));
+            }
+            _ => return Err(RuntimeError::InvalidInstruction(instruction)),
+        }
 
-                    let value = self.peek(position as usize);
-                    self.stack_set(position as usize, value.clone());
-                }
-                OP_RETURN => {
-                    let result = self.stack_pop();
-                    self.sp = self.bp;
-                    self.stack_push(result.clone());
-                    return result;
+        Ok(None)
+    }
+
+    fn check_limits(&mut self) -> Result<(), RuntimeError> {
+        if let Some(fuel) = self.fuel.as_mut() {
+            if *fuel == 0 {
+                return Err(RuntimeError::OutOfFuel);
+            }
+            *fuel -= 1;
+        }
+
+        if self.steps.is_multiple_of(LIMIT_CHECK_INTERVAL) {
+            if self.cancellation.is_cancelled() {
+                return Err(RuntimeError::Cancelled);
+            }
+
+            if let Some(deadline) = self.deadline {
+                if Instant::now() >= deadline {
+                    return Err(RuntimeError::DeadlineExceeded);
                 }
-                _ => panic!("Unknown instruction {}", instruction),
             }
         }
+        self.steps += 1;
+
+        Ok(())
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No active call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("No active call frame")
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.bytecode()[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_address(&mut self) -> usize {
+        let frame = self.frame_mut();
+        let address = decode_address(&frame.bytecode()[frame.ip..]);
+        frame.ip += 2;
+        address
     }
 
-    fn comparison_operation(&mut self, op: ComparisonOperation) -> Value {
+    fn constant(&self, position: u8) -> Value {
+        self.frame().function.constants[position as usize].clone()
+    }
+
+    fn comparison_operation(&mut self, op: ComparisonOperation) -> Result<Value, RuntimeError> {
         let val2 = self.stack_pop();
         let val1 = self.stack_pop();
 
         if let (Value::Boolean { val: bool1 }, Value::Boolean { val: bool2 }) = (&val1, &val2) {
-            VM::comparision_fn(op, bool1, bool2)
+            Ok(VM::comparision_fn(op, bool1, bool2))
         } else if let (Value::Number { val: num1 }, Value::Number { val: num2 }) = (&val1, &val2) {
-            VM::comparision_fn(op, num1, num2)
+            Ok(VM::comparision_fn(op, num1, num2))
         } else if let (Value::String { val: str1 }, Value::String { val: str2 }) = (&val1, &val2) {
-            VM::comparision_fn(op, str1, str2)
+            Ok(VM::comparision_fn(op, str1, str2))
+        } else if let ComparisonOperation::Equal = op {
+            // Other values have no order, only equality, and values of
+            // different types are never equal.
+            Ok(boolean(val1 == val2))
         } else {
-            panic!("Invalid operands");
+            Err(invalid_operands())
         }
     }
 
@@ -224,55 +757,180 @@ impl VM {
         }
     }
 
-    fn math_operation(&mut self, op: MathOperation) -> Value {
+    fn math_operation(&mut self, op: MathOperation) -> Result<Value, RuntimeError> {
         let val2 = self.stack_pop();
         let val1 = self.stack_pop();
 
         if let (Value::Number { val: num1 }, Value::Number { val: num2 }) = (&val1, &val2) {
-            match op {
-                MathOperation::ADD => number(num1 + num2),
-                MathOperation::SUB => number(num1 - num2),
-                MathOperation::MUL => number(num1 * num2),
-                MathOperation::DIV => number(num1 / num2),
+            if let (MathOperation::Div, 0.0) = (&op, num2) {
+                return Err(error("division-by-zero", "Division by zero"));
             }
+
+            Ok(match op {
+                MathOperation::Add => number(num1 + num2),
+                MathOperation::Sub => number(num1 - num2),
+                MathOperation::Mul => number(num1 * num2),
+                MathOperation::Div => number(num1 / num2),
+            })
         } else if let (Value::String { val: str1 }, Value::String { val: str2 }) = (&val1, &val2) {
             match op {
-                MathOperation::ADD => {
+                MathOperation::Add => {
+                    // Fail before allocating, so a runaway concatenation
+                    // can't take the host down with it.
+                    self.check_heap(str1.len() + str2.len())?;
+
                     let mut result = str1.clone();
-                    result.push_str(&str2);
-                    string(result)
+                    result.push_str(str2);
+                    Ok(string(result))
                 }
-                _ => panic!("Invalid operands"),
+                _ => Err(invalid_operands()),
             }
         } else {
-            panic!("Invalid operands");
+            Err(invalid_operands())
         }
     }
 
+    /// Unwinds to the innermost handler and continues there with `value` on
+    /// top of the stack.
+    fn throw(&mut self, value: Value) -> Result<Option<Value>, RuntimeError> {
+        let handler = self.handlers.pop().expect("No exception handler");
+
+        while self.frames.len() > handler.frames {
+            self.frames.pop();
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.exit();
+            }
+        }
+        self.bp = self.frame().bp;
+
+        self.stack_truncate(handler.stack_len);
+        self.stack_push(value)?;
+        self.frame_mut().ip = handler.address;
+
+        Ok(None)
+    }
+
     fn stack_pop(&mut self) -> Value {
-        self.sp -= 1;
+        let value = self.stack.pop().expect("Stack underflow");
+        self.release(&value);
+        value
+    }
 
-        if let Some(value) = &self.stack[self.sp] {
-            value.clone()
-        } else {
-            panic!("Stack underflow");
+    /// Pops the top `count` values, returning them in stack order.
+    fn stack_pop_many(&mut self, count: usize) -> Vec<Value> {
+        let values: Vec<Value> = self.stack.drain(self.stack.len() - count..).collect();
+        for value in &values {
+            self.release(value);
         }
+        values
     }
 
-    fn stack_push(&mut self, value: Value) {
-        self.stack[self.sp] = Some(value);
-        self.sp += 1;
+    fn stack_push(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if self.stack.len() >= self.max_stack_size {
+            return Err(RuntimeError::StackOverflow);
+        }
+
+        self.check_heap(self.added_bytes(&value))?;
+        self.charge(&value);
+        self.stack.push(value);
+
+        self.memory_usage.peak_heap_bytes = self.memory_usage.peak_heap_bytes.max(self.heap_bytes);
+        self.memory_usage.peak_stack_size = self.memory_usage.peak_stack_size.max(self.stack.len());
+
+        Ok(())
+    }
+
+    fn stack_truncate(&mut self, len: usize) {
+        let values: Vec<Value> = self.stack.drain(len..).collect();
+        for value in &values {
+            self.release(value);
+        }
     }
 
     fn peek(&mut self, offset: usize) -> Value {
-        if let Some(value) = &self.stack[self.bp + offset] {
-            value.clone()
-        } else {
-            panic!("Invalid stack offset");
+        self.stack[self.bp + offset].clone()
+    }
+
+    fn stack_set(&mut self, offset: usize, value: Value) -> Result<(), RuntimeError> {
+        let previous = mem::replace(
+            &mut self.stack[self.bp + offset],
+            Value::Boolean { val: false },
+        );
+        self.release(&previous);
+
+        if let Err(err) = self.check_heap(self.added_bytes(&value)) {
+            self.charge(&previous);
+            self.stack[self.bp + offset] = previous;
+            return Err(err);
         }
+
+        self.charge(&value);
+        self.stack[self.bp + offset] = value;
+
+        self.memory_usage.peak_heap_bytes = self.memory_usage.peak_heap_bytes.max(self.heap_bytes);
+
+        Ok(())
     }
 
-    fn stack_set(&mut self, offset: usize, value: Value) {
-        self.stack[self.bp + offset] = Some(value);
+    /// Bytes that putting `value` in another stack slot adds to the heap
+    /// count. A list some slot already holds is counted once, however many
+    /// slots share it.
+    fn added_bytes(&self, value: &Value) -> usize {
+        match value {
+            Value::List { val } if self.lists.contains_key(&Arc::as_ptr(val)) => 0,
+            value => value.heap_size(),
+        }
     }
+
+    fn charge(&mut self, value: &Value) {
+        let Value::List { val } = value else {
+            self.heap_bytes += value.heap_size();
+            return;
+        };
+
+        let (slots, bytes) = self
+            .lists
+            .entry(Arc::as_ptr(val))
+            .or_insert_with(|| (0, value.heap_size()));
+        if *slots == 0 {
+            self.heap_bytes += *bytes;
+        }
+        *slots += 1;
+    }
+
+    fn release(&mut self, value: &Value) {
+        let Value::List { val } = value else {
+            self.heap_bytes -= value.heap_size();
+            return;
+        };
+
+        let key = Arc::as_ptr(val);
+        let (slots, bytes) = self.lists.get_mut(&key).expect("List was not counted");
+        *slots -= 1;
+        if *slots == 0 {
+            self.heap_bytes -= *bytes;
+            self.lists.remove(&key);
+        }
+    }
+
+    fn check_heap(&self, additional_bytes: usize) -> Result<(), RuntimeError> {
+        match self.max_heap_bytes {
+            Some(max_heap_bytes) if self.heap_bytes + additional_bytes > max_heap_bytes => {
+                Err(RuntimeError::OutOfMemory)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A `(kind "message")` error value, thrown for errors of the program.
+fn error(kind: &str, message: &str) -> RuntimeError {
+    RuntimeError::Exception(Box::new(list(vec![
+        symbol(kind),
+        string(message.to_string()),
+    ])))
+}
+
+fn invalid_operands() -> RuntimeError {
+    error("type-error", "Invalid operands")
 }
@@ -0,0 +1,131 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A built-in function over numbers, called with `OP_NATIVE` and the
+/// native's index in `NATIVES`.
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    function: fn(&mut Random, &[f64]) -> f64,
+}
+
+impl Native {
+    pub fn call(&self, random: &mut Random, arguments: &[f64]) -> f64 {
+        (self.function)(random, arguments)
+    }
+}
+
+pub const NATIVES: &[Native] = &[
+    Native {
+        name: "sqrt",
+        arity: 1,
+        function: |_, args| args[0].sqrt(),
+    },
+    Native {
+        name: "abs",
+        arity: 1,
+        function: |_, args| args[0].abs(),
+    },
+    Native {
+        name: "floor",
+        arity: 1,
+        function: |_, args| args[0].floor(),
+    },
+    Native {
+        name: "ceil",
+        arity: 1,
+        function: |_, args| args[0].ceil(),
+    },
+    Native {
+        name: "round",
+        arity: 1,
+        function: |_, args| args[0].round(),
+    },
+    Native {
+        name: "sin",
+        arity: 1,
+        function: |_, args| args[0].sin(),
+    },
+    Native {
+        name: "cos",
+        arity: 1,
+        function: |_, args| args[0].cos(),
+    },
+    Native {
+        name: "tan",
+        arity: 1,
+        function: |_, args| args[0].tan(),
+    },
+    Native {
+        name: "atan2",
+        arity: 2,
+        function: |_, args| args[0].atan2(args[1]),
+    },
+    Native {
+        name: "log",
+        arity: 1,
+        function: |_, args| args[0].ln(),
+    },
+    Native {
+        name: "exp",
+        arity: 1,
+        function: |_, args| args[0].exp(),
+    },
+    Native {
+        name: "min",
+        arity: 2,
+        function: |_, args| args[0].min(args[1]),
+    },
+    Native {
+        name: "max",
+        arity: 2,
+        function: |_, args| args[0].max(args[1]),
+    },
+    Native {
+        name: "random",
+        arity: 0,
+        function: |random, _| random.next_f64(),
+    },
+];
+
+/// The index of the native called `name`.
+pub fn native_index(name: &str) -> Option<u8> {
+    NATIVES
+        .iter()
+        .position(|native| native.name == name)
+        .map(|index| index as u8)
+}
+
+/// A splitmix64 generator. The same seed always yields the same numbers,
+/// on every platform.
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random { state: seed }
+    }
+
+    /// Seeded from the clock, for programs that don't need to be
+    /// reproducible.
+    pub fn from_time() -> Random {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos() as u64);
+        Random::new(nanos)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::disassembler::op_code_name;
+
+const HOT_ADDRESSES: usize = 10;
+
+#[derive(Debug, Clone, Default)]
+pub struct FunctionProfile {
+    pub calls: u64,
+    pub instructions: u64,
+    /// Time spent running the function's own instructions.
+    pub self_time: Duration,
+    /// Time from entering the function until it returned, including the
+    /// functions it called. Recursive calls are only counted once.
+    pub total_time: Duration,
+}
+
+struct ActiveFrame {
+    function: usize,
+    stack: usize,
+    entered: Instant,
+}
+
+/// Collects per-opcode, per-function and per-address execution counts while
+/// the VM runs. Attach one with `VM::set_profiler` before calling `exec`.
+///
+/// Functions and call stacks are numbered as they are entered, so counting
+/// an instruction doesn't build any strings.
+#[derive(Default)]
+pub struct Profiler {
+    op_codes: HashMap<u8, u64>,
+    names: Vec<String>,
+    function_ids: HashMap<String, usize>,
+    functions: Vec<FunctionProfile>,
+    addresses: HashMap<(usize, usize), u64>,
+    // Each call stack is its caller's stack plus a function, outermost
+    // function first.
+    stacks: Vec<(Option<usize>, usize)>,
+    stack_ids: HashMap<(Option<usize>, usize), usize>,
+    stack_counts: Vec<u64>,
+    frames: Vec<ActiveFrame>,
+    last_instruction: Option<Instant>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    pub fn op_code_counts(&self) -> &HashMap<u8, u64> {
+        &self.op_codes
+    }
+
+    pub fn functions(&self) -> HashMap<&str, &FunctionProfile> {
+        self.names
+            .iter()
+            .map(String::as_str)
+            .zip(&self.functions)
+            .collect()
+    }
+
+    pub(super) fn enter(&mut self, name: &str) {
+        self.charge_self_time();
+
+        let function = match self.function_ids.get(name) {
+            Some(&function) => function,
+            None => {
+                self.names.push(name.to_string());
+                self.functions.push(FunctionProfile::default());
+                self.function_ids
+                    .insert(name.to_string(), self.names.len() - 1);
+                self.names.len() - 1
+            }
+        };
+
+        let key = (self.frames.last().map(|frame| frame.stack), function);
+        let stack = *self.stack_ids.entry(key).or_insert_with(|| {
+            self.stacks.push(key);
+            self.stack_counts.push(0);
+            self.stacks.len() - 1
+        });
+
+        self.functions[function].calls += 1;
+        self.frames.push(ActiveFrame {
+            function,
+            stack,
+            entered: Instant::now(),
+        });
+    }
+
+    pub(super) fn exit(&mut self) {
+        self.charge_self_time();
+
+        if let Some(frame) = self.frames.pop() {
+            let is_recursive = self
+                .frames
+                .iter()
+                .any(|active| active.function == frame.function);
+            if !is_recursive {
+                self.functions[frame.function].total_time += frame.entered.elapsed();
+            }
+        }
+    }
+
+    /// Closes every frame still open, e.g. after a `HALT` or an error.
+    pub(super) fn finish(&mut self) {
+        while !self.frames.is_empty() {
+            self.exit();
+        }
+        self.last_instruction = None;
+    }
+
+    pub(super) fn instruction(&mut self, address: usize, op_code: u8) {
+        self.charge_self_time();
+
+        let Some(frame) = self.frames.last() else {
+            return;
+        };
+
+        *self.op_codes.entry(op_code).or_default() += 1;
+        self.functions[frame.function].instructions += 1;
+        *self.addresses.entry((frame.function, address)).or_default() += 1;
+        self.stack_counts[frame.stack] += 1;
+    }
+
+    fn charge_self_time(&mut self) {
+        let now = Instant::now();
+
+        if let (Some(last), Some(frame)) = (self.last_instruction, self.frames.last()) {
+            self.functions[frame.function].self_time += now - last;
+        }
+
+        self.last_instruction = Some(now);
+    }
+
+    /// The names of the functions on `stack`, joined with `;`.
+    fn stack_name(&self, stack: usize) -> String {
+        let mut names = vec![];
+        let mut stack = Some(stack);
+        while let Some(index) = stack {
+            let (caller, function) = self.stacks[index];
+            names.push(self.names[function].as_str());
+            stack = caller;
+        }
+        names.reverse();
+        names.join(";")
+    }
+
+    pub fn write_report<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let total: u64 = self.op_codes.values().sum();
+
+        writeln!(writer, "{:<16}{:>12}{:>9}", "Instruction", "Count", "%")?;
+        let mut op_codes: Vec<(&u8, &u64)> = self.op_codes.iter().collect();
+        op_codes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (op_code, count) in op_codes {
+            writeln!(
+                writer,
+                "{:<16}{:>12}{:>8.1}%",
+                op_code_name(*op_code),
+                count,
+                percentage(*count, total)
+            )?;
+        }
+        writeln!(writer, "{:<16}{:>12}", "Total", total)?;
+
+        writeln!(
+            writer,
+            "\n{:<16}{:>8}{:>14}{:>14}{:>14}",
+            "Function", "Calls", "Instructions", "Self (ms)", "Total (ms)"
+        )?;
+        let mut functions: Vec<(&str, &FunctionProfile)> = self.functions().into_iter().collect();
+        functions.sort_by(|a, b| b.1.self_time.cmp(&a.1.self_time).then(a.0.cmp(b.0)));
+        for (name, profile) in functions {
+            writeln!(
+                writer,
+                "{:<16}{:>8}{:>14}{:>14.3}{:>14.3}",
+                name,
+                profile.calls,
+                profile.instructions,
+                profile.self_time.as_secs_f64() * 1000.0,
+                profile.total_time.as_secs_f64() * 1000.0
+            )?;
+        }
+
+        writeln!(writer, "\n{:<24}{:>12}{:>9}", "Address", "Count", "%")?;
+        let mut addresses: Vec<((&str, usize), u64)> = self
+            .addresses
+            .iter()
+            .map(|(&(function, address), &count)| ((self.names[function].as_str(), address), count))
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for ((name, address), count) in addresses.into_iter().take(HOT_ADDRESSES) {
+            writeln!(
+                writer,
+                "{:<24}{:>12}{:>8.1}%",
+                format!("{}@{:04x}", name, address),
+                count,
+                percentage(count, total)
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes one `stack count` line per call stack, the input format of
+    /// flamegraph tools. Counts are executed instructions.
+    pub fn write_folded<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut stacks: Vec<(String, u64)> = self
+            .stack_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(stack, &count)| (self.stack_name(stack), count))
+            .collect();
+        stacks.sort();
+
+        for (stack, count) in stacks {
+            writeln!(writer, "{} {}", stack, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn percentage(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
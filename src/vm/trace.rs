@@ -0,0 +1,87 @@
+use std::io::Write;
+
+use crate::{disassembler::op_code_name, value::Value};
+
+use super::{decode_address, is_jump, operand_count, CallFrame};
+
+// Longer strings are cut short so that one instruction stays on one line.
+const MAX_STRING_WIDTH: usize = 16;
+
+/// Logs every executed instruction together with the live part of the
+/// stack. Write errors are ignored so tracing never changes how a program
+/// runs.
+pub struct Trace {
+    writer: Box<dyn Write>,
+    functions: Vec<String>,
+}
+
+impl Trace {
+    pub fn new(writer: Box<dyn Write>) -> Trace {
+        Trace {
+            writer,
+            functions: vec![],
+        }
+    }
+
+    /// Only logs instructions of the named function; can be called more
+    /// than once to trace several functions.
+    pub fn function(mut self, name: &str) -> Trace {
+        self.functions.push(name.to_string());
+        self
+    }
+
+    pub(super) fn log(&mut self, frame: &CallFrame, stack: &[Value]) {
+        if !self.functions.is_empty() && !self.functions.iter().any(|name| name == frame.name()) {
+            return;
+        }
+
+        let Some(&op_code) = frame.bytecode().get(frame.ip) else {
+            return;
+        };
+        let operands = if is_jump(op_code) && frame.ip + 2 < frame.bytecode().len() {
+            format!("{:04x}", decode_address(&frame.bytecode()[frame.ip + 1..]))
+        } else {
+            frame
+                .bytecode()
+                .iter()
+                .skip(frame.ip + 1)
+                .take(operand_count(op_code).unwrap_or(0))
+                .map(|operand| operand.to_string())
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        let name = if operand_count(op_code).is_some() {
+            op_code_name(op_code)
+        } else {
+            format!(".byte {:02x}", op_code)
+        };
+        let live_stack = stack[frame.bp..]
+            .iter()
+            .map(compact)
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let _ = writeln!(
+            self.writer,
+            "{:<12}{:04x}  {:<14}{:<8}[{}]",
+            frame.name(),
+            frame.ip,
+            name,
+            operands,
+            live_stack
+        );
+    }
+}
+
+fn compact(value: &Value) -> String {
+    match value {
+        Value::String { val } if val.chars().count() > MAX_STRING_WIDTH => {
+            let prefix: String = val.chars().take(MAX_STRING_WIDTH).collect();
+            format!("{:?}...", prefix)
+        }
+        Value::String { val } => format!("{:?}", val),
+        Value::Function { val } => format!("<{}>", val.name),
+        Value::Symbol { .. } | Value::List { .. } => format!("'{}", value),
+        _ => value.to_string(),
+    }
+}